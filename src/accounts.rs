@@ -0,0 +1,186 @@
+use crate::types::PermissionType;
+use crate::util::escape_path;
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// Result of comparing an old and new account database by name
+#[derive(Debug, Default, PartialEq)]
+pub struct AccountDiff {
+    /// (name, old id, new id) for every name whose id changed
+    pub changed: Vec<(String, u32, u32)>,
+    /// Names that only exist in the old file
+    pub only_old: Vec<String>,
+    /// Names that only exist in the new file
+    pub only_new: Vec<String>,
+}
+
+/// Parses the contents of a `passwd` or `group` file into a map of name:id.
+/// Both formats have the name in the first field and the id in the third.
+///
+/// # Arguments
+///
+/// * `contents` - Contents of the file
+///
+fn parse_accounts(contents: &str) -> Result<BTreeMap<String, u32>, anyhow::Error> {
+    let mut accounts = BTreeMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() < 3 {
+            bail!("line {} -> Invalid entry: '{line}'", i + 1);
+        }
+        let id = match fields[2].parse::<u32>() {
+            Ok(o) => o,
+            Err(_) => bail!(
+                "line {} -> Unable to parse id string '{}' to int",
+                i + 1,
+                fields[2]
+            ),
+        };
+        if let Some(previous) = accounts.insert(fields[0].to_string(), id) {
+            if previous != id {
+                bail!(
+                    "line {} -> Name '{}' appears with both id '{previous}' and '{id}'",
+                    i + 1,
+                    fields[0]
+                );
+            }
+        }
+    }
+    Ok(accounts)
+}
+
+/// Reads and parses a `passwd` or `group` file
+///
+/// # Arguments
+///
+/// * `path` - Path to the file
+///
+fn read_accounts(path: &Path) -> Result<BTreeMap<String, u32>, anyhow::Error> {
+    let contents = match fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) => bail!("{} -> Failed to read account file: {e}", escape_path(path)),
+    };
+    match parse_accounts(&contents) {
+        Ok(a) => Ok(a),
+        Err(e) => bail!("{}: {e}", escape_path(path)),
+    }
+}
+
+/// Compares two account maps by name
+///
+/// # Arguments
+///
+/// * `old` - Map of name:id before the migration
+///
+/// * `new` - Map of name:id after the migration
+///
+fn diff_accounts(old: &BTreeMap<String, u32>, new: &BTreeMap<String, u32>) -> AccountDiff {
+    let mut diff = AccountDiff::default();
+    for (name, old_id) in old {
+        match new.get(name) {
+            Some(new_id) if new_id != old_id => {
+                diff.changed.push((name.clone(), *old_id, *new_id));
+            }
+            Some(_) => (),
+            None => diff.only_old.push(name.clone()),
+        }
+    }
+    for name in new.keys() {
+        if !old.contains_key(name) {
+            diff.only_new.push(name.clone());
+        }
+    }
+    diff
+}
+
+/// Compares an old and new `passwd` or `group` file and prints the changed ids
+/// in the format accepted by `--map-file`. Names that only exist on one side
+/// are reported on stderr.
+///
+/// # Arguments
+///
+/// * `old_path` - Path to the old file
+///
+/// * `new_path` - Path to the new file
+///
+/// * `ptype` - User for `passwd` files, Group for `group` files
+///
+pub fn print_mapping(
+    old_path: &Path,
+    new_path: &Path,
+    ptype: PermissionType,
+) -> Result<(), anyhow::Error> {
+    let old = read_accounts(old_path)?;
+    let new = read_accounts(new_path)?;
+    let diff = diff_accounts(&old, &new);
+
+    let kind = match ptype {
+        PermissionType::User => "uid",
+        PermissionType::Group => "gid",
+    };
+    println!(
+        "# {kind} mapping from {} -> {}",
+        escape_path(old_path),
+        escape_path(new_path)
+    );
+    for (name, old_id, new_id) in &diff.changed {
+        println!("{kind} {old_id} {new_id} # {name}");
+    }
+    for name in &diff.only_old {
+        eprintln!(
+            "{} -> {} '{name}' only exists in the old file",
+            escape_path(old_path),
+            ptype
+        );
+    }
+    for name in &diff.only_new {
+        eprintln!(
+            "{} -> {} '{name}' only exists in the new file",
+            escape_path(new_path),
+            ptype
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_accounts_reads_passwd_and_group() {
+        let passwd =
+            "root:x:0:0:root:/root:/bin/bash\n\n# comment\nalice:x:57:57::/home/alice:/bin/sh\n";
+        let group = "root:x:0:\nproject:x:890:alice,bob\n";
+        assert_eq!(
+            parse_accounts(passwd).unwrap(),
+            BTreeMap::from([("root".to_string(), 0), ("alice".to_string(), 57)])
+        );
+        assert_eq!(
+            parse_accounts(group).unwrap(),
+            BTreeMap::from([("root".to_string(), 0), ("project".to_string(), 890)])
+        );
+        assert!(parse_accounts("alice:x\n").is_err());
+        assert!(parse_accounts("alice:x:abc:0::\n").is_err());
+    }
+
+    #[test]
+    fn diff_accounts_matches_by_name() {
+        let old = parse_accounts("root:x:0:0\nalice:x:57:57\nbob:x:58:58\n").unwrap();
+        let new = parse_accounts("root:x:0:0\nalice:x:219883:100\ncarol:x:219884:100\n").unwrap();
+        assert_eq!(
+            diff_accounts(&old, &new),
+            AccountDiff {
+                changed: vec![("alice".to_string(), 57, 219883)],
+                only_old: vec!["bob".to_string()],
+                only_new: vec!["carol".to_string()],
+            }
+        );
+    }
+}
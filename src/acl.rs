@@ -1,38 +1,211 @@
 use crate::ctx::Ctx;
+use crate::errors::errno_of;
+use crate::events::{Event, EventKind};
+use crate::fsobject::{list_xattrs, proc_path, set_xattr, FsObject};
+use crate::nfs4;
 use crate::types::{AclType, PermissionType};
+use crate::util::escape_path;
 
-use posix_acl::{ACLEntry, PosixACL, Qualifier};
+use anyhow::{anyhow, bail, Result};
+use clap::ValueEnum;
+use nix::libc;
+use posix_acl::{ACLEntry, PosixACL, Qualifier, ACL_EXECUTE, ACL_READ, ACL_WRITE};
+use std::collections::HashMap;
+use std::os::fd::OwnedFd;
 use std::path::Path;
 
-/// Returns the User or Group `PosixACL` at the given path
+/// Version of the `system.posix_acl_*` xattr format
+const ACL_XATTR_VERSION: u32 = 2;
+/// Entry tags of the xattr format, entries are sorted by these
+const ACL_TAG_USER_OBJ: u16 = 0x01;
+const ACL_TAG_USER: u16 = 0x02;
+const ACL_TAG_GROUP_OBJ: u16 = 0x04;
+const ACL_TAG_GROUP: u16 = 0x08;
+const ACL_TAG_MASK: u16 = 0x10;
+const ACL_TAG_OTHER: u16 = 0x20;
+/// Id of the entries that don't name a user or group
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+
+/// Returns the User or Group `PosixACL` of the given object.
+/// If the filesystem doesn't support ACLs, the device is remembered and
+/// reported once instead of failing for every object on it.
 ///
 /// # Arguments
 ///
+/// * `ctx` - Context object used throughout the application
+///
 /// * `atype` - Type of ACL you expect, either User or Group
 ///
-/// * `path` - Path to the filesystem object
+/// * `obj` - The filesystem object
 ///
-fn get_acl(atype: AclType, path: &Path) -> Option<PosixACL> {
-    if path.is_symlink() {
-        return None;
-    }
+/// * `acl_path` - Path that reaches the object through an open handle, see `fsobject::proc_path`
+///
+fn get_acl(ctx: &Ctx, atype: AclType, obj: &FsObject, acl_path: &Path) -> Option<PosixACL> {
+    let path = obj.path;
     let acl = match atype {
-        AclType::Access => PosixACL::read_acl(path),
-        AclType::Default => PosixACL::read_default_acl(path),
+        AclType::Access => PosixACL::read_acl(acl_path),
+        AclType::Default => PosixACL::read_default_acl(acl_path),
     };
     match acl {
         Ok(acl) => return Some(acl),
         Err(e) => {
-            eprintln!("{} -> Error reading ACL: {e}", path.display());
+            let e = anyhow::Error::new(e)
+                .context(format!("{} -> Error reading ACL", escape_path(path)));
+            if errno_of(&e) == Some(libc::EOPNOTSUPP) {
+                if ctx.acl_support.mark_unsupported(obj.stat.st_dev, path) {
+                    ctx.verbose_printer.print1(format!(
+                        "{} -> ACLs are not supported on this filesystem, skipping them on it",
+                        escape_path(path)
+                    ));
+                }
+                return None;
+            }
+            ctx.error(path, &e);
             return None;
         }
     };
 }
 
-// TODO(lcrown): This is a mess, why do we need entry? ugh. fix it.
-//
-/// Do the work to set the ACL according to all the provided data.
-/// Returns true if any changes were made, otherwise false.
+/// How a rewritten ACL entry is merged with an existing entry for its new id
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ConflictPolicy {
+    /// Grant the permissions of both entries
+    Union,
+    /// Keep the permissions of the existing entry
+    Keep,
+    /// Take the permissions of the migrated entry
+    Take,
+    /// Leave the whole ACL unchanged and report an error
+    Fail,
+}
+
+impl ConflictPolicy {
+    /// Short lowercase name, used in messages and events
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConflictPolicy::Union => "union",
+            ConflictPolicy::Keep => "keep",
+            ConflictPolicy::Take => "take",
+            ConflictPolicy::Fail => "fail",
+        }
+    }
+}
+
+/// A named ACL entry whose id is in the mappings
+#[derive(Debug, PartialEq)]
+struct AclRemap {
+    ptype: PermissionType,
+    old_id: u32,
+    new_id: u32,
+    perm: u32,
+}
+
+/// Splits the entries of an ACL into the ones that stay as they are and the
+/// named entries whose id is in the mappings. Every lookup uses the id from the
+/// original ACL, so swaps like `1:2,2:1` and chains like `1:2,2:3` move each
+/// entry exactly once, the same as the ownership of a file.
+///
+/// # Arguments
+///
+/// * `entries` - Entries returned from `PosixACL::entries()`
+///
+/// * `uidmap` - Map of old:new uids
+///
+/// * `gidmap` - Map of old:new gids
+///
+fn remap_entries(
+    entries: Vec<ACLEntry>,
+    uidmap: &HashMap<u32, u32>,
+    gidmap: &HashMap<u32, u32>,
+) -> (Vec<ACLEntry>, Vec<AclRemap>) {
+    let mut kept = vec![];
+    let mut remaps = vec![];
+    for entry in entries {
+        let (ptype, old_id, new_id) = match entry.qual {
+            Qualifier::User(uid) => (PermissionType::User, uid, uidmap.get(&uid)),
+            Qualifier::Group(gid) => (PermissionType::Group, gid, gidmap.get(&gid)),
+            _ => {
+                kept.push(entry);
+                continue;
+            }
+        };
+        match new_id {
+            Some(new_id) => remaps.push(AclRemap {
+                ptype,
+                old_id,
+                new_id: *new_id,
+                perm: entry.perm,
+            }),
+            None => kept.push(entry),
+        }
+    }
+    (kept, remaps)
+}
+
+/// A migrated ACL entry whose new id already has an entry
+#[derive(Debug, PartialEq)]
+struct AclConflict {
+    ptype: PermissionType,
+    old_id: u32,
+    new_id: u32,
+    /// Permissions of the entry already present for `new_id`
+    existing: u32,
+    /// Permissions of the entry migrated from `old_id`
+    migrated: u32,
+}
+
+/// Builds the rewritten ACL from the output of `remap_entries`, merging
+/// entries whose new id is already present according to `policy`.
+/// Returns the ACL and every conflict found along the way.
+///
+/// # Arguments
+///
+/// * `kept` - Entries that stay as they are
+///
+/// * `remaps` - Entries whose id is replaced
+///
+/// * `policy` - How conflicting permissions are merged
+///
+fn remapped_acl(
+    kept: Vec<ACLEntry>,
+    remaps: &[AclRemap],
+    policy: ConflictPolicy,
+) -> (PosixACL, Vec<AclConflict>) {
+    let mut acl = PosixACL::empty();
+    let mut conflicts = vec![];
+    for entry in kept {
+        acl.set(entry.qual, entry.perm);
+    }
+    for r in remaps {
+        let qual = || match r.ptype {
+            PermissionType::User => Qualifier::User(r.new_id),
+            PermissionType::Group => Qualifier::Group(r.new_id),
+        };
+        let perm = match acl.get(qual()) {
+            Some(existing) => {
+                conflicts.push(AclConflict {
+                    ptype: r.ptype,
+                    old_id: r.old_id,
+                    new_id: r.new_id,
+                    existing,
+                    migrated: r.perm,
+                });
+                match policy {
+                    ConflictPolicy::Union => existing | r.perm,
+                    ConflictPolicy::Keep => existing,
+                    ConflictPolicy::Take | ConflictPolicy::Fail => r.perm,
+                }
+            }
+            None => r.perm,
+        };
+        acl.set(qual(), perm);
+    }
+    (acl, conflicts)
+}
+
+/// Reports a conflict between a migrated ACL entry and an existing one and counts it.
+/// Like errors, every conflict is printed to stderr regardless of verbosity,
+/// since the policy may have changed what the entries grant.
 ///
 /// # Arguments
 ///
@@ -40,75 +213,140 @@ fn get_acl(atype: AclType, path: &Path) -> Option<PosixACL> {
 ///
 /// * `path` - Path to the filesystem object
 ///
-/// * `acl` - Mutable reference to the `PosixACL` that we want to change
+/// * `acl_type` - Whether the entries are in the access or default ACL
 ///
-/// * `current_id` - ID of the current object, either UID or GID
+/// * `conflict` - The conflicting entries
 ///
-/// * `entry` - The ACL entry itself, for getting the permission for resetting it
+fn report_conflict(ctx: &Ctx, path: &Path, acl_type: AclType, conflict: &AclConflict) {
+    let vp = &ctx.verbose_printer;
+    eprintln!(
+        "{} -> {} ACL already has {} id {} ({}), migrated from id {} ({}), resolving with {}",
+        escape_path(path),
+        acl_type.as_str(),
+        conflict.ptype,
+        conflict.new_id,
+        perm_to_string(conflict.existing),
+        conflict.old_id,
+        perm_to_string(conflict.migrated),
+        ctx.acl_conflict.as_str(),
+    );
+    ctx.stats.add_acl_conflict();
+    vp.event(Event {
+        ptype: Some(conflict.ptype),
+        old_id: Some(conflict.old_id),
+        new_id: Some(conflict.new_id),
+        acl_type: Some(acl_type),
+        noop: ctx.noop,
+        reason: Some(ctx.acl_conflict.as_str()),
+        ..Event::new(EventKind::AclConflict, path)
+    });
+}
+
+/// Counts a remapped ACL entry and emits its event, once it was written
+/// or would have been in noop mode
 ///
-/// * `ptype` - The permission type that you want changed, User or Group
+/// # Arguments
 ///
-fn set_acl_permission(
-    ctx: &Ctx,
-    path: &Path,
-    acl: &mut PosixACL,
-    acl_type: AclType,
-    current_id: u32,
-    entry: ACLEntry,
-    ptype: PermissionType,
-) -> bool {
+/// * `ctx` - Context object used throughout the application
+///
+/// * `path` - Path to the filesystem object
+///
+/// * `acl_type` - Whether the entry is in the access or default ACL
+///
+/// * `remap` - The entry that was remapped
+///
+fn report_remap(ctx: &Ctx, path: &Path, acl_type: AclType, remap: &AclRemap) {
     let vp = &ctx.verbose_printer;
-    let atype_str = match acl_type {
-        AclType::Access => "access",
-        AclType::Default => "default",
-    };
-    let new_id = match ptype {
-        PermissionType::User => ctx.uidmap.get(&current_id),
-        PermissionType::Group => ctx.gidmap.get(&current_id),
-    };
-    match new_id {
-        Some(new_id) => {
-            vp.print1(format!(
-                "{} -> {} id {} found in {} ACL, replacing with uid {}",
-                path.display(),
-                ptype,
-                current_id,
-                atype_str,
-                new_id,
-            ));
-            if ctx.noop {
-                vp.print1(format!("{} -> NOOP: Not making changes", path.display()));
-                return false;
+    ctx.stats.add_acl_entry(remap.ptype, remap.old_id, acl_type);
+    vp.event(Event {
+        ptype: Some(remap.ptype),
+        old_id: Some(remap.old_id),
+        new_id: Some(remap.new_id),
+        acl_type: Some(acl_type),
+        noop: ctx.noop,
+        ..Event::new(EventKind::Acl, path)
+    });
+}
+
+/// A group class entry whose effective permissions differ after rewriting
+#[derive(Debug, PartialEq)]
+struct EffectiveChange {
+    ptype: PermissionType,
+    /// Id of a named entry before mapping, None for the owning group
+    old_id: Option<u32>,
+    /// Id of a named entry after mapping, None for the owning group
+    new_id: Option<u32>,
+    before: u32,
+    after: u32,
+}
+
+/// Returns the permissions the mask of an ACL lets through, everything without a mask
+fn mask_of(entries: &[ACLEntry]) -> u32 {
+    for entry in entries {
+        if entry.qual == Qualifier::Mask {
+            return entry.perm;
+        }
+    }
+    ACL_READ | ACL_WRITE | ACL_EXECUTE
+}
+
+/// Compares the effective permissions, the entry limited by the mask, of every
+/// group class entry in the original ACL with its mapped entry in the rewritten one
+///
+/// # Arguments
+///
+/// * `original` - Entries of the ACL as read from disk
+///
+/// * `rewritten` - Entries of the ACL about to be written
+///
+/// * `uidmap` - Map of old:new uids
+///
+/// * `gidmap` - Map of old:new gids
+///
+fn effective_changes(
+    original: &[ACLEntry],
+    rewritten: &[ACLEntry],
+    uidmap: &HashMap<u32, u32>,
+    gidmap: &HashMap<u32, u32>,
+) -> Vec<EffectiveChange> {
+    let (mask_before, mask_after) = (mask_of(original), mask_of(rewritten));
+    let mut changes = vec![];
+    for entry in original {
+        let (ptype, old_id, qual) = match entry.qual {
+            Qualifier::User(uid) => {
+                let new = *uidmap.get(&uid).unwrap_or(&uid);
+                (PermissionType::User, Some(uid), Qualifier::User(new))
             }
-            vp.print1(format!(
-                "{} -> Adding {} ACL for new {} id: {}",
-                path.display(),
-                atype_str,
-                ptype,
-                new_id,
-            ));
-            match ptype {
-                PermissionType::User => acl.set(Qualifier::User(*new_id), entry.perm),
-                PermissionType::Group => acl.set(Qualifier::Group(*new_id), entry.perm),
+            Qualifier::Group(gid) => {
+                let new = *gidmap.get(&gid).unwrap_or(&gid);
+                (PermissionType::Group, Some(gid), Qualifier::Group(new))
             }
-            vp.print1(format!(
-                "{} -> Removing {} ACL for old {} id: {}",
-                path.display(),
-                atype_str,
-                ptype,
-                current_id,
-            ));
-            match ptype {
-                PermissionType::User => acl.remove(Qualifier::User(current_id)),
-                PermissionType::Group => acl.remove(Qualifier::Group(current_id)),
+            Qualifier::GroupObj => (PermissionType::Group, None, Qualifier::GroupObj),
+            _ => continue,
+        };
+        let after = match rewritten.iter().find(|e| e.qual == qual) {
+            Some(e) => e.perm & mask_after,
+            None => 0,
+        };
+        let before = entry.perm & mask_before;
+        if before != after {
+            let new_id = match qual {
+                Qualifier::User(id) | Qualifier::Group(id) => Some(id),
+                _ => None,
             };
-            return true;
+            changes.push(EffectiveChange {
+                ptype,
+                old_id,
+                new_id,
+                before,
+                after,
+            });
         }
-        None => return false,
     }
+    changes
 }
 
-/// Write the ACL data, essentially "saving" it
+/// Reports a change in effective permissions caused by rewriting an ACL and counts it
 ///
 /// # Arguments
 ///
@@ -116,136 +354,611 @@ fn set_acl_permission(
 ///
 /// * `path` - Path to the filesystem object
 ///
-/// * `acl` - Mutable reference to the `PosixACL` you want to save
+/// * `acl_type` - Whether the entry is in the access or default ACL
+///
+/// * `change` - The entry whose effective permissions changed
 ///
-fn write_acl(ctx: &Ctx, path: &Path, acl: &mut PosixACL, acl_type: AclType) {
+fn report_effective_change(ctx: &Ctx, path: &Path, acl_type: AclType, change: &EffectiveChange) {
     let vp = &ctx.verbose_printer;
-    vp.print1(format!("{} -> Writing changes to ACL", path.display()));
-    let res = match acl_type {
-        AclType::Access => acl.write_acl(path),
-        AclType::Default => acl.write_default_acl(path),
+    let entry = match change.new_id {
+        Some(id) => format!("{} id {id}", change.ptype),
+        None => String::from("owning group"),
     };
-    match res {
-        Ok(_) => vp.print1(format!(
-            "{} -> Successfully wrote changes to ACL",
-            path.display()
-        )),
-        Err(e) => eprintln!("{} -> Failed to write acl: {e}", path.display()),
-    }
+    vp.print1(format!(
+        "{} -> Effective permissions of {entry} in {} ACL change from {} to {}",
+        escape_path(path),
+        acl_type.as_str(),
+        perm_to_string(change.before),
+        perm_to_string(change.after),
+    ));
+    ctx.stats.add_effective_change();
+    vp.event(Event {
+        ptype: Some(change.ptype),
+        old_id: change.old_id,
+        new_id: change.new_id,
+        acl_type: Some(acl_type),
+        noop: ctx.noop,
+        old_perm: Some(change.before),
+        new_perm: Some(change.after),
+        ..Event::new(EventKind::AclEffective, path)
+    });
 }
 
-/// Using the data in our context, update the ACLs on the given `path`
+/// Rewrites the ACL of the given type on the object according to the mappings.
+/// Returns false if the ACL couldn't be read.
 ///
 /// # Arguments
 ///
 /// * `ctx` - Context object used throughout the application
 ///
-/// * `path` - Path to the filesystem object
+/// * `obj` - The filesystem object
+///
+/// * `fd` - Handle on the object, see `FsObject::open_path`
+///
+/// * `acl_type` - Which of the ACLs to rewrite
 ///
-pub fn update_acl(ctx: &Ctx, path: &Path) {
+fn update_acl_type(ctx: &Ctx, obj: &FsObject, fd: &OwnedFd, acl_type: AclType) -> bool {
     let vp = &ctx.verbose_printer;
-    vp.print1(format!("{} -> Scanning ACLs", path.display()));
-
-    // get the acl, if it's none, it already printed an error, lets just skip the file
-    let mut access_acl = match get_acl(AclType::Access, path) {
+    let path = obj.path;
+    // get the acl, if it's none, it already printed an error
+    let original = match get_acl(ctx, acl_type, obj, &proc_path(fd)) {
         Some(acl) => acl,
-        None => return,
+        None => return false,
     };
 
-    // flag that only writes access acl if any changes were made
-    let mut access_changed = false;
-
-    // go through each entry and:
-    //  - if the current uid matches one of the current uids in our map
-    //      - add the new uid with the same permission
-    //      - remove the old uid
-    //  - if the current gid matches one of the current gids in our map
-    //      - add the new gid with the same permission
-    //      - remove the old gid
-    for entry in access_acl.entries() {
-        match entry.qual {
-            Qualifier::User(current_uid) => {
-                if set_acl_permission(
-                    &ctx,
-                    path,
-                    &mut access_acl,
-                    AclType::Access,
-                    current_uid,
-                    entry,
-                    PermissionType::User,
-                ) {
-                    access_changed = true
-                }
-            }
-            Qualifier::Group(current_gid) => {
-                if set_acl_permission(
-                    &ctx,
-                    path,
-                    &mut access_acl,
-                    AclType::Access,
-                    current_gid,
-                    entry,
-                    PermissionType::Group,
-                ) {
-                    access_changed = true
-                }
+    let (kept, remaps) = remap_entries(original.entries(), &ctx.uidmap, &ctx.gidmap);
+    if remaps.is_empty() {
+        return true;
+    }
+    let (mut acl, conflicts) = remapped_acl(kept, &remaps, ctx.acl_conflict);
+    for c in &conflicts {
+        report_conflict(ctx, path, acl_type, c);
+    }
+    if !conflicts.is_empty() && ctx.acl_conflict == ConflictPolicy::Fail {
+        let e = anyhow!(
+            "{} -> {} ACL has conflicting entries for new ids, not rewriting it",
+            escape_path(path),
+            acl_type.as_str()
+        );
+        ctx.error(path, &e);
+        return true;
+    }
+    if ctx.recalculate_mask {
+        acl.fix_mask();
+    }
+    // catch a broken ACL here rather than as a failed write
+    if let Err(e) = acl.validate() {
+        let e = anyhow::Error::new(e).context(format!(
+            "{} -> Rewritten {} ACL '{}' is invalid, not writing it",
+            escape_path(path),
+            acl_type.as_str(),
+            entries_to_string(&acl.entries())
+        ));
+        ctx.error(path, &e);
+        return true;
+    }
+    for r in &remaps {
+        vp.print1(format!(
+            "{} -> {} id {} found in {} ACL, replacing with id {}",
+            escape_path(path),
+            r.ptype,
+            r.old_id,
+            acl_type.as_str(),
+            r.new_id,
+        ));
+    }
+    let entries = acl.entries();
+    let changes = effective_changes(&original.entries(), &entries, &ctx.uidmap, &ctx.gidmap);
+    if ctx.noop {
+        vp.print1(format!("{} -> NOOP: Not making changes", escape_path(path)));
+    } else if !write_acl(ctx, obj, fd, &entries, acl_type) {
+        return true;
+    }
+    // only count what actually changed, like ownership
+    for r in &remaps {
+        report_remap(ctx, path, acl_type, r);
+    }
+    for c in &changes {
+        report_effective_change(ctx, path, acl_type, c);
+    }
+    true
+}
+
+/// Write the ACL data, essentially "saving" it.
+/// The entries are written as they are, the mask is not recomputed.
+/// Returns true if the ACL was written.
+///
+/// # Arguments
+///
+/// * `ctx` - Context object used throughout the application
+///
+/// * `obj` - The filesystem object
+///
+/// * `fd` - Handle on the object, see `FsObject::open_path`
+///
+/// * `entries` - Entries of the ACL you want to save
+///
+fn write_acl(
+    ctx: &Ctx,
+    obj: &FsObject,
+    fd: &OwnedFd,
+    entries: &[ACLEntry],
+    acl_type: AclType,
+) -> bool {
+    let vp = &ctx.verbose_printer;
+    let path = obj.path;
+
+    // record the ACL as it currently exists on disk before overwriting it,
+    // if we can't record it, we don't touch it
+    if let Some(journal) = &ctx.journal {
+        let prior = match get_acl(ctx, acl_type, obj, &proc_path(fd)) {
+            Some(acl) => acl,
+            None => return false,
+        };
+        let (dev, ino) = (obj.stat.st_dev, obj.stat.st_ino);
+        if let Err(e) = journal.record_acl(path, acl_type, dev, ino, &prior.entries()) {
+            ctx.error(path, &e);
+            return false;
+        }
+    }
+
+    vp.print1(format!("{} -> Writing changes to ACL", escape_path(path)));
+    // PosixACL::write_acl always recomputes the mask, so write the xattr directly
+    match set_xattr(fd, acl_type.xattr_name(), &acl_to_xattr(entries)) {
+        Ok(_) => {
+            vp.print1(format!(
+                "{} -> Successfully wrote changes to ACL",
+                escape_path(path)
+            ));
+            true
+        }
+        Err(e) => {
+            let e = anyhow::Error::new(e)
+                .context(format!("{} -> Failed to write acl", escape_path(path)));
+            ctx.error(path, &e);
+            false
+        }
+    }
+}
+
+/// ACL xattrs present on an object, from a single `listxattr`, so objects with
+/// only the base permissions don't pay for reading and parsing their ACLs
+pub struct AclXattrs {
+    names: Vec<Vec<u8>>,
+    /// Set for the first object on a device, whose ACLs are read regardless,
+    /// since a filesystem without ACL support doesn't list any
+    probe: bool,
+}
+
+impl AclXattrs {
+    /// Lists the xattrs of the object, None if that failed, the error is already reported
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - Context object used throughout the application
+    ///
+    /// * `obj` - The filesystem object
+    ///
+    /// * `fd` - Handle on the object, see `FsObject::open_path`
+    ///
+    pub fn list(ctx: &Ctx, obj: &FsObject, fd: &OwnedFd) -> Option<AclXattrs> {
+        match list_xattrs(fd) {
+            Ok(names) => Some(AclXattrs {
+                names,
+                probe: ctx.acl_support.first_on_device(obj.stat.st_dev),
+            }),
+            Err(e) => {
+                let e = anyhow::Error::new(e)
+                    .context(format!("{} -> Error listing xattrs", escape_path(obj.path)));
+                ctx.error(obj.path, &e);
+                None
             }
-            _ => (),
         }
     }
 
-    // write changes to the access acl
-    if access_changed {
-        write_acl(&ctx, path, &mut access_acl, AclType::Access);
+    /// Returns true if the ACL stored in the xattr `name` should be read
+    pub fn has(&self, name: &str) -> bool {
+        self.probe || self.names.iter().any(|n| n == name.as_bytes())
     }
+}
 
-    // if it's not a directory, we don't need to update the default acl
-    if !path.is_dir() {
+/// Using the data in our context, update the ACLs on the given object
+///
+/// # Arguments
+///
+/// * `ctx` - Context object used throughout the application
+///
+/// * `obj` - The filesystem object
+///
+pub fn update_acl(ctx: &Ctx, obj: &FsObject) {
+    // symlinks don't have ACLs of their own
+    if obj.is_symlink() {
         return;
     }
+    let vp = &ctx.verbose_printer;
+    let path = obj.path;
+    vp.print1(format!("{} -> Scanning ACLs", escape_path(path)));
 
-    // flag that only writes access acl if any changes were made
-    let mut default_changed = false;
-
-    let mut default_acl = match get_acl(AclType::Default, path) {
-        Some(acl) => acl,
+    // the ACL functions only take paths, so go through a handle on the object
+    // rather than resolving the full path again
+    let fd = match obj.open_path() {
+        Ok(fd) => fd,
+        Err(e) => {
+            ctx.error(path, &e);
+            return;
+        }
+    };
+    let xattrs = match AclXattrs::list(ctx, obj, &fd) {
+        Some(x) => x,
         None => return,
     };
-    // run the write against AclType::Default
-    for entry in default_acl.entries() {
-        match entry.qual {
-            Qualifier::User(current_uid) => {
-                if set_acl_permission(
-                    &ctx,
-                    path,
-                    &mut default_acl,
-                    AclType::Default,
-                    current_uid,
-                    entry,
-                    PermissionType::User,
-                ) {
-                    default_changed = true
-                }
+
+    if !ctx.acl_support.is_unsupported(obj.stat.st_dev) {
+        // a directory can have a default ACL without an extended access ACL,
+        // so only a failed read of the access ACL skips the default one
+        let access_read = !xattrs.has(AclType::Access.xattr_name())
+            || update_acl_type(ctx, obj, &fd, AclType::Access);
+        // if it's not a directory, we don't need to update the default acl
+        if access_read && obj.is_dir() && xattrs.has(AclType::Default.xattr_name()) {
+            update_acl_type(ctx, obj, &fd, AclType::Default);
+        }
+    }
+
+    // NFSv4 ACLs are separate xattrs, independent of the POSIX ones
+    nfs4::update_nfs4_acl(ctx, obj, &fd, &xattrs);
+}
+
+/// Returns the named user and group ids referenced in the ACLs of the object,
+/// the default ACL is only read for directories
+///
+/// # Arguments
+///
+/// * `ctx` - Context object used throughout the application
+///
+/// * `obj` - The filesystem object
+///
+pub fn acl_ids(ctx: &Ctx, obj: &FsObject) -> Vec<(AclType, PermissionType, u32)> {
+    let mut ids = vec![];
+    if obj.is_symlink() || ctx.acl_support.is_unsupported(obj.stat.st_dev) {
+        return ids;
+    }
+    let fd = match obj.open_path() {
+        Ok(fd) => fd,
+        Err(e) => {
+            ctx.error(obj.path, &e);
+            return ids;
+        }
+    };
+    let xattrs = match AclXattrs::list(ctx, obj, &fd) {
+        Some(x) => x,
+        None => return ids,
+    };
+    let acl_path = proc_path(&fd);
+    let atypes: &[AclType] = match obj.is_dir() {
+        true => &[AclType::Access, AclType::Default],
+        false => &[AclType::Access],
+    };
+    for atype in atypes {
+        if !xattrs.has(atype.xattr_name()) {
+            continue;
+        }
+        let acl = match get_acl(ctx, *atype, obj, &acl_path) {
+            Some(acl) => acl,
+            None => continue,
+        };
+        for entry in acl.entries() {
+            match entry.qual {
+                Qualifier::User(uid) => ids.push((*atype, PermissionType::User, uid)),
+                Qualifier::Group(gid) => ids.push((*atype, PermissionType::Group, gid)),
+                _ => (),
             }
-            Qualifier::Group(current_gid) => {
-                if set_acl_permission(
-                    &ctx,
-                    path,
-                    &mut default_acl,
-                    AclType::Default,
-                    current_gid,
-                    entry,
-                    PermissionType::Group,
-                ) {
-                    default_changed = true
+        }
+    }
+    ids
+}
+
+/// Returns a compact, numeric, single-line representation of ACL entries,
+/// similar to `getfacl -cn` output joined with commas.
+///
+/// # Example
+///
+/// ```
+/// // u::rwx,u:57:r--,g::r-x,m::r-x,o::---
+/// ```
+///
+/// # Arguments
+///
+/// * `entries` - Entries returned from `PosixACL::entries()`
+///
+pub fn entries_to_string(entries: &[ACLEntry]) -> String {
+    let mut out: Vec<String> = vec![];
+    for entry in entries {
+        let (tag, qual) = match entry.qual {
+            Qualifier::UserObj => ("u", String::new()),
+            Qualifier::User(uid) => ("u", uid.to_string()),
+            Qualifier::GroupObj => ("g", String::new()),
+            Qualifier::Group(gid) => ("g", gid.to_string()),
+            Qualifier::Mask => ("m", String::new()),
+            Qualifier::Other => ("o", String::new()),
+            Qualifier::Undefined => continue,
+        };
+        out.push(format!("{tag}:{qual}:{}", perm_to_string(entry.perm)));
+    }
+    out.join(",")
+}
+
+/// Encodes ACL entries as the value of the `system.posix_acl_*` xattrs,
+/// a version header followed by the entries sorted by tag and id
+///
+/// # Arguments
+///
+/// * `entries` - Entries returned from `PosixACL::entries()`
+///
+pub fn acl_to_xattr(entries: &[ACLEntry]) -> Vec<u8> {
+    let mut raw: Vec<(u16, u32, u32)> = vec![];
+    for entry in entries {
+        let (tag, id) = match entry.qual {
+            Qualifier::UserObj => (ACL_TAG_USER_OBJ, ACL_UNDEFINED_ID),
+            Qualifier::User(uid) => (ACL_TAG_USER, uid),
+            Qualifier::GroupObj => (ACL_TAG_GROUP_OBJ, ACL_UNDEFINED_ID),
+            Qualifier::Group(gid) => (ACL_TAG_GROUP, gid),
+            Qualifier::Mask => (ACL_TAG_MASK, ACL_UNDEFINED_ID),
+            Qualifier::Other => (ACL_TAG_OTHER, ACL_UNDEFINED_ID),
+            Qualifier::Undefined => continue,
+        };
+        raw.push((tag, id, entry.perm));
+    }
+    raw.sort();
+    let mut value = ACL_XATTR_VERSION.to_le_bytes().to_vec();
+    for (tag, id, perm) in raw {
+        value.extend_from_slice(&tag.to_le_bytes());
+        value.extend_from_slice(&(perm as u16).to_le_bytes());
+        value.extend_from_slice(&id.to_le_bytes());
+    }
+    value
+}
+
+/// Returns `rwx` style text for an ACL permission
+pub fn perm_to_string(perm: u32) -> String {
+    let mut s = String::new();
+    s.push(if perm & ACL_READ != 0 { 'r' } else { '-' });
+    s.push(if perm & ACL_WRITE != 0 { 'w' } else { '-' });
+    s.push(if perm & ACL_EXECUTE != 0 { 'x' } else { '-' });
+    s
+}
+
+/// Parses the output of `entries_to_string` back into a `PosixACL`
+///
+/// # Arguments
+///
+/// * `text` - Compact ACL text, such as `u::rwx,u:57:r--,g::r-x,m::r-x,o::---`
+///
+pub fn acl_from_string(text: &str) -> Result<PosixACL, anyhow::Error> {
+    let mut acl = PosixACL::empty();
+    for entry in text.split(',') {
+        let fields: Vec<&str> = entry.split(':').collect();
+        if fields.len() != 3 || fields[2].len() != 3 {
+            bail!("Invalid ACL entry '{entry}'");
+        }
+        let qual = match (fields[0], fields[1]) {
+            ("u", "") => Qualifier::UserObj,
+            ("g", "") => Qualifier::GroupObj,
+            ("m", "") => Qualifier::Mask,
+            ("o", "") => Qualifier::Other,
+            ("u", id) | ("g", id) => {
+                let id = match id.parse::<u32>() {
+                    Ok(id) => id,
+                    Err(_) => bail!("Unable to parse id string '{id}' to int"),
+                };
+                match fields[0] {
+                    "u" => Qualifier::User(id),
+                    _ => Qualifier::Group(id),
                 }
             }
-            _ => (),
+            _ => bail!("Invalid ACL entry '{entry}'"),
+        };
+        let mut perm = 0;
+        let bits = [('r', ACL_READ), ('w', ACL_WRITE), ('x', ACL_EXECUTE)];
+        for (c, (expected, bit)) in fields[2].chars().zip(bits) {
+            if c == expected {
+                perm |= bit;
+            } else if c != '-' {
+                bail!("Invalid ACL permission '{}' in '{entry}'", fields[2]);
+            }
+        }
+        acl.set(qual, perm);
+    }
+    Ok(acl)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(uid: u32, perm: u32) -> ACLEntry {
+        ACLEntry {
+            qual: Qualifier::User(uid),
+            perm,
         }
     }
 
-    // otherwise we should write the changes
-    if default_changed {
-        write_acl(&ctx, path, &mut default_acl, AclType::Default);
+    fn rewrite(text: &str, uidmap: &HashMap<u32, u32>) -> String {
+        let acl = acl_from_string(text).unwrap();
+        let (kept, remaps) = remap_entries(acl.entries(), uidmap, &HashMap::new());
+        let (acl, conflicts) = remapped_acl(kept, &remaps, ConflictPolicy::Fail);
+        assert!(conflicts.is_empty());
+        entries_to_string(&acl.entries())
+    }
+
+    #[test]
+    fn remaps_each_entry_once() {
+        let swap = HashMap::from([(1, 2), (2, 1)]);
+        assert_eq!(
+            rewrite("u::rwx,u:1:r--,u:2:-w-,g::r-x,m::rwx,o::---", &swap),
+            "u::rwx,u:1:-w-,u:2:r--,g::r-x,m::rwx,o::---"
+        );
+        let chain = HashMap::from([(1, 2), (2, 3)]);
+        assert_eq!(
+            rewrite("u::rwx,u:1:r--,u:2:-w-,g::r-x,m::rwx,o::---", &chain),
+            "u::rwx,u:2:r--,u:3:-w-,g::r-x,m::rwx,o::---"
+        );
+    }
+
+    #[test]
+    fn keeps_unmapped_entries() {
+        let (kept, remaps) = remap_entries(
+            vec![user(1, ACL_READ), user(5, ACL_WRITE)],
+            &HashMap::from([(1, 2)]),
+            &HashMap::new(),
+        );
+        assert_eq!(kept, vec![user(5, ACL_WRITE)]);
+        assert_eq!(
+            remaps,
+            vec![AclRemap {
+                ptype: PermissionType::User,
+                old_id: 1,
+                new_id: 2,
+                perm: ACL_READ,
+            }]
+        );
+    }
+
+    #[test]
+    fn resolves_conflicts_by_policy() {
+        // 57 is migrated to 219883, which already has an entry
+        let text = "u::rwx,u:57:r--,u:219883:-w-,g::r-x,m::rwx,o::---";
+        let uidmap = HashMap::from([(57, 219883)]);
+        for (policy, expected) in [
+            (ConflictPolicy::Union, "rw-"),
+            (ConflictPolicy::Keep, "-w-"),
+            (ConflictPolicy::Take, "r--"),
+        ] {
+            let acl = acl_from_string(text).unwrap();
+            let (kept, remaps) = remap_entries(acl.entries(), &uidmap, &HashMap::new());
+            let (acl, conflicts) = remapped_acl(kept, &remaps, policy);
+            assert_eq!(
+                conflicts,
+                vec![AclConflict {
+                    ptype: PermissionType::User,
+                    old_id: 57,
+                    new_id: 219883,
+                    existing: ACL_WRITE,
+                    migrated: ACL_READ,
+                }]
+            );
+            assert_eq!(
+                entries_to_string(&acl.entries()),
+                format!("u::rwx,u:219883:{expected},g::r-x,m::rwx,o::---")
+            );
+        }
+    }
+
+    #[test]
+    fn encodes_xattr_sorted_by_tag_and_id() {
+        let acl = acl_from_string("o::---,u:9:r--,u::rwx,u:3:-w-,m::rw-,g::r--").unwrap();
+        let value = acl_to_xattr(&acl.entries());
+        assert_eq!(value.len(), 4 + 6 * 8);
+        let tags: Vec<(u16, u32)> = value[4..]
+            .chunks(8)
+            .map(|e| {
+                let tag = u16::from_le_bytes([e[0], e[1]]);
+                (tag, u32::from_le_bytes([e[4], e[5], e[6], e[7]]))
+            })
+            .collect();
+        assert_eq!(
+            tags,
+            [
+                (ACL_TAG_USER_OBJ, ACL_UNDEFINED_ID),
+                (ACL_TAG_USER, 3),
+                (ACL_TAG_USER, 9),
+                (ACL_TAG_GROUP_OBJ, ACL_UNDEFINED_ID),
+                (ACL_TAG_MASK, ACL_UNDEFINED_ID),
+                (ACL_TAG_OTHER, ACL_UNDEFINED_ID),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_effective_changes_through_mask() {
+        let uidmap = HashMap::from([(1, 2)]);
+        let original = acl_from_string("u::rw-,u:1:r--,u:2:-w-,g::r--,m::r--,o::---").unwrap();
+        // union merged -w- into the mask-limited entry, and the mask was recomputed
+        let rewritten = acl_from_string("u::rw-,u:2:rw-,g::r--,m::rw-,o::---").unwrap();
+        let changes = effective_changes(
+            &original.entries(),
+            &rewritten.entries(),
+            &uidmap,
+            &HashMap::new(),
+        );
+        assert_eq!(
+            changes,
+            vec![
+                EffectiveChange {
+                    ptype: PermissionType::User,
+                    old_id: Some(1),
+                    new_id: Some(2),
+                    before: ACL_READ,
+                    after: ACL_READ | ACL_WRITE,
+                },
+                EffectiveChange {
+                    ptype: PermissionType::User,
+                    old_id: Some(2),
+                    new_id: Some(2),
+                    before: 0,
+                    after: ACL_READ | ACL_WRITE,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_only_listed_acls_unless_probing() {
+        let xattrs = AclXattrs {
+            names: vec![
+                b"user.comment".to_vec(),
+                b"system.posix_acl_default".to_vec(),
+            ],
+            probe: false,
+        };
+        assert!(xattrs.has(AclType::Default.xattr_name()));
+        assert!(!xattrs.has(AclType::Access.xattr_name()));
+        assert!(!xattrs.has(nfs4::NFS4_ACL_XATTR));
+        assert!(!xattrs.has(nfs4::NFS4_ACL_XDR_XATTR));
+
+        let probe = AclXattrs {
+            names: vec![],
+            probe: true,
+        };
+        assert!(probe.has(AclType::Access.xattr_name()));
+        assert!(probe.has(nfs4::NFS4_ACL_XATTR));
+    }
+
+    #[test]
+    fn lists_default_acl_without_access_acl() {
+        let dir = std::env::temp_dir().join(format!("chowner-xattrs-{}", std::process::id()));
+        std::fs::create_dir(&dir).unwrap();
+        let acl = acl_from_string("u::rwx,u:1:r--,g::r-x,m::r-x,o::---").unwrap();
+        let set = xattr::set(
+            &dir,
+            AclType::Default.xattr_name(),
+            &acl_to_xattr(&acl.entries()),
+        );
+        let listed = set.map(|_| {
+            let obj = FsObject::base(&dir).unwrap();
+            list_xattrs(&obj.open_path().unwrap()).unwrap()
+        });
+        std::fs::remove_dir(&dir).unwrap();
+        let names = match listed {
+            Ok(names) => names,
+            // the temp dir is on a filesystem without ACLs, nothing to test
+            Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => return,
+            Err(e) => panic!("{e}"),
+        };
+        let xattrs = AclXattrs {
+            names,
+            probe: false,
+        };
+        assert!(xattrs.has(AclType::Default.xattr_name()));
+        assert!(!xattrs.has(AclType::Access.xattr_name()));
     }
 }
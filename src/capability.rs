@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+
+/// Extended attribute holding the file capabilities of an executable
+pub const CAPABILITY_XATTR: &str = "security.capability";
+
+/// Revision field of `vfs_cap_data.magic_etc`
+const VFS_CAP_REVISION_MASK: u32 = 0xFF00_0000;
+/// v3 capabilities carry the root uid of the user namespace they apply to
+const VFS_CAP_REVISION_3: u32 = 0x0300_0000;
+/// Size of a v3 `vfs_ns_cap_data`, the root uid is the last field
+const VFS_CAP_U32_3_SIZE: usize = 24;
+
+/// Returns the namespace root uid of a v3 `security.capability` value,
+/// or None for older revisions which don't have one
+///
+/// # Arguments
+///
+/// * `value` - Raw value of the `security.capability` xattr
+///
+pub fn rootid(value: &[u8]) -> Option<u32> {
+    if value.len() != VFS_CAP_U32_3_SIZE {
+        return None;
+    }
+    let magic = u32::from_le_bytes(value[0..4].try_into().ok()?);
+    if magic & VFS_CAP_REVISION_MASK != VFS_CAP_REVISION_3 {
+        return None;
+    }
+    Some(u32::from_le_bytes(value[20..24].try_into().ok()?))
+}
+
+/// Returns the capability value with its namespace root uid rewritten through `uidmap`,
+/// or None if it has no root uid or the root uid isn't mapped
+///
+/// # Arguments
+///
+/// * `value` - Raw value of the `security.capability` xattr
+///
+/// * `uidmap` - Map of old:new uids
+///
+pub fn remap_rootid(value: &[u8], uidmap: &HashMap<u32, u32>) -> Option<(u32, u32, Vec<u8>)> {
+    let old = rootid(value)?;
+    let new = *uidmap.get(&old)?;
+    let mut remapped = value.to_vec();
+    remapped[20..24].copy_from_slice(&new.to_le_bytes());
+    Some((old, new, remapped))
+}
+
+/// Returns the lowercase hex encoding of `bytes`, for the journal
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Parses the output of `to_hex`
+pub fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v3_caps(rootid: u32) -> Vec<u8> {
+        let mut value = vec![];
+        value.extend_from_slice(&(VFS_CAP_REVISION_3 | 1).to_le_bytes());
+        value.extend_from_slice(&(1u32 << 13).to_le_bytes()); // cap_net_raw
+        value.extend_from_slice(&[0; 12]);
+        value.extend_from_slice(&rootid.to_le_bytes());
+        value
+    }
+
+    #[test]
+    fn remaps_v3_rootid() {
+        let uidmap = HashMap::from([(57, 219883)]);
+        let (old, new, remapped) = remap_rootid(&v3_caps(57), &uidmap).unwrap();
+        assert_eq!((old, new), (57, 219883));
+        assert_eq!(rootid(&remapped), Some(219883));
+        assert_eq!(remapped[..20], v3_caps(57)[..20]);
+
+        assert!(remap_rootid(&v3_caps(58), &uidmap).is_none());
+        // v2 has no root uid
+        let v2 = [
+            0x01, 0, 0, 0x02, 0, 0x20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        assert!(remap_rootid(&v2, &uidmap).is_none());
+    }
+
+    #[test]
+    fn hex_roundtrips() {
+        let value = v3_caps(219883);
+        assert_eq!(from_hex(&to_hex(&value)), Some(value));
+        assert_eq!(from_hex("0"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+}
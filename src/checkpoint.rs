@@ -0,0 +1,336 @@
+use crate::errors::EXIT_ABORTED;
+use crate::util::{absolute_path, escape_path, lock, read, unescape_path, write};
+use anyhow::{anyhow, bail, Context, Result};
+use nix::sys::signal::{SigSet, Signal};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard};
+use std::thread;
+use std::time::Duration;
+
+/// Progress of a run, saved so an interrupted run can be resumed.
+///
+/// Directories whose whole subtree was processed are recorded, along with
+/// every object processed inside a directory that isn't complete yet, since
+/// mappings with swaps or chains must never be applied to an object twice.
+/// A directory replaces its children once it completes, so the file stays
+/// small even on huge trees.
+#[derive(Debug)]
+pub struct Checkpoint {
+    /// Where the checkpoint is saved
+    path: PathBuf,
+    /// Fingerprint of the uid and gid mappings the progress belongs to
+    fingerprint: String,
+    /// Everything that was done, behind one lock so a save sees a consistent snapshot
+    progress: Mutex<Progress>,
+    /// Held for reading while an object is changed and recorded, and for writing
+    /// when the run is interrupted, so no change is lost between the two
+    busy: RwLock<()>,
+    /// Serializes writers, so an older snapshot never replaces a newer one
+    save_lock: Mutex<()>,
+}
+
+/// The state saved in a checkpoint
+#[derive(Debug, Default)]
+struct Progress {
+    /// Absolute paths of the topmost completed directories
+    completed: HashSet<PathBuf>,
+    /// Absolute paths of objects processed in directories that aren't completed yet
+    processed: HashSet<PathBuf>,
+    /// Device and inode of processed objects with several hard links,
+    /// kept for the whole run since other links may be anywhere in the tree
+    links: HashSet<(u64, u64)>,
+}
+
+/// Returns a fingerprint of the mappings that doesn't depend on their order,
+/// FNV-1a over the sorted pairs so it is stable between builds
+///
+/// # Arguments
+///
+/// * `uidmap` - Map of old:new uids
+///
+/// * `gidmap` - Map of old:new gids
+///
+pub fn fingerprint(uidmap: &HashMap<u32, u32>, gidmap: &HashMap<u32, u32>) -> String {
+    let mut lines: Vec<String> = vec![];
+    for (kind, idmap) in [("uid", uidmap), ("gid", gidmap)] {
+        for (old, new) in idmap {
+            lines.push(format!("{kind} {old} {new}"));
+        }
+    }
+    lines.sort();
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in lines.join("\n").bytes() {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    format!("{hash:016x}")
+}
+
+impl Checkpoint {
+    /// Starts a new checkpoint with no progress
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Where the checkpoint is saved
+    ///
+    /// * `fingerprint` - Fingerprint of the mappings, see `fingerprint`
+    ///
+    pub fn new(path: &Path, fingerprint: String) -> Checkpoint {
+        Checkpoint {
+            path: path.to_path_buf(),
+            fingerprint,
+            progress: Mutex::new(Progress::default()),
+            busy: RwLock::new(()),
+            save_lock: Mutex::new(()),
+        }
+    }
+
+    /// Loads the progress of a previous run, refusing if the mappings changed since
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Checkpoint written by a previous run
+    ///
+    /// * `fingerprint` - Fingerprint of the current mappings, see `fingerprint`
+    ///
+    pub fn resume(path: &Path, fingerprint: String) -> Result<Checkpoint> {
+        let file = File::open(path)
+            .with_context(|| format!("{} -> Failed to open checkpoint", escape_path(path)))?;
+        let mut saved_fingerprint = None;
+        let mut progress = Progress::default();
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line
+                .with_context(|| format!("{} -> Failed to read checkpoint", escape_path(path)))?;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                anyhow!(
+                    "{}:{} -> Invalid checkpoint entry: '{line}'",
+                    escape_path(path),
+                    i + 1
+                )
+            };
+            match line.split_once('\t') {
+                Some(("mapping", f)) => saved_fingerprint = Some(f.to_string()),
+                Some(("done", p)) => {
+                    progress.completed.insert(unescape_path(p)?);
+                }
+                Some(("processed", p)) => {
+                    progress.processed.insert(unescape_path(p)?);
+                }
+                Some(("link", l)) => {
+                    let (dev, ino) = l.split_once('\t').ok_or_else(invalid)?;
+                    let dev = dev.parse().map_err(|_| invalid())?;
+                    let ino = ino.parse().map_err(|_| invalid())?;
+                    progress.links.insert((dev, ino));
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        match saved_fingerprint {
+            Some(f) if f == fingerprint => (),
+            Some(_) => bail!(
+                "{} -> The uid/gid mapping changed since the checkpoint was written, refusing to resume",
+                escape_path(path)
+            ),
+            None => bail!(
+                "{} -> Checkpoint has no mapping fingerprint",
+                escape_path(path)
+            ),
+        }
+        Ok(Checkpoint {
+            progress: Mutex::new(progress),
+            ..Checkpoint::new(path, fingerprint)
+        })
+    }
+
+    fn lock_progress(&self) -> MutexGuard<'_, Progress> {
+        lock(&self.progress)
+    }
+
+    /// Returns a guard to hold while an object is changed and recorded with `processed`,
+    /// an interrupted run waits for it before saving
+    pub fn busy(&self) -> RwLockReadGuard<'_, ()> {
+        read(&self.busy)
+    }
+
+    /// Returns true if the subtree at `path` was completed by a previous run
+    pub fn is_completed(&self, path: &Path) -> bool {
+        self.lock_progress()
+            .completed
+            .contains(&absolute_path(path))
+    }
+
+    /// Returns true if the object at `path` itself was processed by a previous run
+    pub fn is_processed(&self, path: &Path) -> bool {
+        self.lock_progress()
+            .processed
+            .contains(&absolute_path(path))
+    }
+
+    /// Returns true if an object with several hard links was processed by a previous run
+    pub fn is_link_processed(&self, dev: u64, ino: u64) -> bool {
+        self.lock_progress().links.contains(&(dev, ino))
+    }
+
+    /// Marks the object at `path` as processed, its subtree may still be incomplete
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the object
+    ///
+    /// * `link` - Device and inode of the object if it has several hard links
+    ///
+    pub fn processed(&self, path: &Path, link: Option<(u64, u64)>) {
+        let path = absolute_path(path);
+        let mut progress = self.lock_progress();
+        progress.processed.insert(path);
+        if let Some(link) = link {
+            progress.links.insert(link);
+        }
+    }
+
+    /// Marks the directory at `path` as completed, replacing its children
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the directory
+    ///
+    /// * `children` - Names of the entries in the directory
+    ///
+    pub fn complete<N: AsRef<Path>>(&self, path: &Path, children: &[N]) {
+        let path = absolute_path(path);
+        let mut progress = self.lock_progress();
+        for name in children {
+            let child = path.join(name);
+            progress.completed.remove(&child);
+            progress.processed.remove(&child);
+        }
+        progress.processed.remove(&path);
+        progress.completed.insert(path);
+    }
+
+    /// Writes the checkpoint, replacing the previous one atomically
+    pub fn save(&self) -> Result<()> {
+        let _guard = lock(&self.save_lock);
+        let mut lines: Vec<String> = vec![];
+        {
+            let progress = self.lock_progress();
+            for p in &progress.completed {
+                lines.push(format!("done\t{}", escape_path(p)));
+            }
+            for p in &progress.processed {
+                lines.push(format!("processed\t{}", escape_path(p)));
+            }
+            for (dev, ino) in &progress.links {
+                lines.push(format!("link\t{dev}\t{ino}"));
+            }
+        }
+        lines.sort();
+
+        let mut text = String::from("# chowner-rs checkpoint\n");
+        text.push_str(&format!("mapping\t{}\n", self.fingerprint));
+        for line in lines {
+            text.push_str(&line);
+            text.push('\n');
+        }
+
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let write = || -> std::io::Result<()> {
+            let mut file = File::create(&tmp)?;
+            file.write_all(text.as_bytes())?;
+            file.sync_data()?;
+            fs::rename(&tmp, &self.path)
+        };
+        write().with_context(|| format!("{} -> Failed to save checkpoint", escape_path(&self.path)))
+    }
+}
+
+/// Saves the checkpoint every `interval` in the background
+pub fn spawn_saver(checkpoint: Arc<Checkpoint>, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        if let Err(e) = checkpoint.save() {
+            eprintln!("{e:?}");
+        }
+    });
+}
+
+/// Saves the checkpoint and exits when SIGINT or SIGTERM arrives.
+/// Both signals must already be blocked in every thread, see `main`,
+/// so only the handler thread started here receives them.
+pub fn spawn_signal_handler(checkpoint: Arc<Checkpoint>) {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGINT);
+    signals.add(Signal::SIGTERM);
+    thread::spawn(move || {
+        let signal = match signals.wait() {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Failed to wait for signals: {e}");
+                return;
+            }
+        };
+        // let the objects being changed get recorded, and keep new ones from starting
+        let _stopped = write(&checkpoint.busy);
+        match checkpoint.save() {
+            Ok(_) => eprintln!(
+                "Received {signal}, progress saved to {}, continue with --resume",
+                escape_path(&checkpoint.path)
+            ),
+            Err(e) => eprintln!("Received {signal}, {e:?}"),
+        }
+        std::process::exit(EXIT_ABORTED as i32);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint_ignores_order_but_not_content() {
+        let a = HashMap::from([(57, 219883), (58, 219884)]);
+        let b = HashMap::from([(58, 219884), (57, 219883)]);
+        let empty = HashMap::new();
+        assert_eq!(fingerprint(&a, &empty), fingerprint(&b, &empty));
+        assert_ne!(fingerprint(&a, &empty), fingerprint(&empty, &a));
+    }
+
+    #[test]
+    fn completed_directory_replaces_children() {
+        let cp = Checkpoint::new(Path::new("/tmp/cp"), String::new());
+        cp.complete(Path::new("/data/a/b"), &["c"]);
+        cp.complete(Path::new("/data/a/d"), &[] as &[&str]);
+        cp.complete(Path::new("/data/a"), &["b", "d", "file"]);
+        assert!(cp.is_completed(Path::new("/data/a")));
+        assert!(!cp.is_completed(Path::new("/data/a/b")));
+        assert_eq!(cp.lock_progress().completed.len(), 1);
+    }
+
+    #[test]
+    fn processed_entries_survive_a_resume_until_their_directory_completes() {
+        let path = std::env::temp_dir().join(format!("chowner-cp-{}", std::process::id()));
+        let cp = Checkpoint::new(&path, "f".into());
+        cp.processed(Path::new("/data/a"), None);
+        cp.processed(Path::new("/data/a/tab\tfile"), Some((39, 7)));
+        cp.save().unwrap();
+
+        let resumed = Checkpoint::resume(&path, "f".into()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(resumed.is_processed(Path::new("/data/a/tab\tfile")));
+        assert!(resumed.is_link_processed(39, 7));
+        assert!(!resumed.is_completed(Path::new("/data/a")));
+
+        resumed.complete(Path::new("/data/a"), &["tab\tfile"]);
+        assert!(resumed.is_completed(Path::new("/data/a")));
+        assert!(resumed.lock_progress().processed.is_empty());
+        assert!(resumed.is_link_processed(39, 7));
+    }
+}
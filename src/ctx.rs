@@ -1,5 +1,17 @@
 use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
 
+use crate::acl::ConflictPolicy;
+use crate::checkpoint::Checkpoint;
+use crate::errors::{errno_of, ErrorRegistry};
+use crate::events::{Event, EventKind};
+use crate::inventory::Inventory;
+use crate::journal::Journal;
+use crate::links::HardLinks;
+use crate::patterns::IgnorePatterns;
+use crate::stats::Stats;
+use crate::support::AclSupport;
 use crate::util::VerbosePrinter;
 
 /// Context structure for storing cross-application data
@@ -11,12 +23,59 @@ pub struct Ctx {
     pub skip_permissions: bool,
     /// If skip_acls, Posix ACLs will be not be modified
     pub skip_acls: bool,
+    /// If drop_setid, setuid/setgid bits cleared by a chown are not put back
+    pub drop_setid: bool,
+    /// How a rewritten ACL entry is merged with an existing entry for the same id
+    pub acl_conflict: ConflictPolicy,
+    /// If recalculate_mask, the mask of a rewritten ACL is recomputed like setfacl does
+    pub recalculate_mask: bool,
     /// Map of old:new uids. Example 57:219883
     pub uidmap: HashMap<u32, u32>,
     /// Map of old:new gids. Example 57:219883
     pub gidmap: HashMap<u32, u32>,
-    /// List of ignored paths
-    pub ignore_paths: Vec<String>,
+    /// If one_file_system, recursion stops at directories on another device than the base path
+    pub one_file_system: bool,
+    /// Hard linked objects already processed
+    pub hard_links: HardLinks,
+    /// Devices whose filesystem doesn't support ACLs
+    pub acl_support: AclSupport,
+    /// Compiled ignore patterns
+    pub ignore_paths: IgnorePatterns,
+    /// If set, every change is recorded here before it is made
+    pub journal: Option<Journal>,
+    /// If set, progress is recorded here so the run can be resumed
+    pub checkpoint: Option<Arc<Checkpoint>>,
+    /// If set, the run only records which ids are in use and changes nothing
+    pub inventory: Option<Inventory>,
+    /// Counters collected during the run
+    pub stats: Stats,
+    /// Every error encountered during the run
+    pub errors: ErrorRegistry,
     /// Reference to a verbose printer
     pub verbose_printer: VerbosePrinter,
 }
+
+impl Ctx {
+    /// Reports an error for `path`: prints it, records it in the error registry
+    /// and emits an error event
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the filesystem object the error relates to
+    ///
+    /// * `e` - The error, usually with the path in its message
+    ///
+    pub fn error(&self, path: &Path, e: &anyhow::Error) {
+        // the root cause carries the OS error text, the layers in between only repeat it
+        let message = match e.chain().count() {
+            1 => e.to_string(),
+            _ => format!("{e}: {}", e.root_cause()),
+        };
+        eprintln!("{message}");
+        self.errors.record(path, errno_of(e));
+        self.verbose_printer.event(Event {
+            error: Some(&message),
+            ..Event::new(EventKind::Error, path)
+        });
+    }
+}
@@ -0,0 +1,187 @@
+use crate::events::OutputFormat;
+use crate::util::{escape_path, insert_path, lock};
+use nix::errno::Errno;
+use serde_json::{json, Map, Value};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Mutex;
+
+/// Exit status when every path was processed without errors
+pub const EXIT_CLEAN: u8 = 0;
+/// Exit status when the run completed, but some paths failed
+pub const EXIT_WITH_ERRORS: u8 = 1;
+/// Exit status when the run could not start or was stopped early
+pub const EXIT_ABORTED: u8 = 2;
+
+thread_local! {
+    /// Errors recorded by the current thread, see `thread_error_count`
+    static THREAD_ERRORS: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Returns the number of errors recorded by the current thread so far.
+/// Comparing it before and after some work tells whether that work failed,
+/// as long as the work doesn't hand anything to other threads.
+pub fn thread_error_count() -> u64 {
+    THREAD_ERRORS.with(|n| n.get())
+}
+
+/// Errors that share an errno
+#[derive(Debug)]
+struct ErrnoGroup {
+    /// Number of errors with this errno
+    count: u64,
+    /// First path that failed with this errno, as an example
+    example: PathBuf,
+}
+
+/// Shared registry of every error encountered during the run, grouped by errno.
+/// Errors without an underlying OS error are grouped under `None`.
+#[derive(Debug, Default)]
+pub struct ErrorRegistry {
+    groups: Mutex<BTreeMap<Option<i32>, ErrnoGroup>>,
+}
+
+/// Returns the first OS errno found in the chain of causes of `e`
+///
+/// # Arguments
+///
+/// * `e` - The error to inspect
+///
+pub fn errno_of(e: &anyhow::Error) -> Option<i32> {
+    for cause in e.chain() {
+        if let Some(io_err) = cause.downcast_ref::<io::Error>() {
+            if let Some(n) = io_err.raw_os_error() {
+                return Some(n);
+            }
+        }
+        if let Some(n) = cause.downcast_ref::<Errno>() {
+            return Some(*n as i32);
+        }
+    }
+    None
+}
+
+/// Returns a short name for an errno group, such as `EACCES (Permission denied)`
+fn errno_name(errno: Option<i32>) -> String {
+    match errno {
+        Some(n) => {
+            let e = Errno::from_i32(n);
+            format!("{:?} ({})", e, e.desc())
+        }
+        None => "other".to_string(),
+    }
+}
+
+impl ErrorRegistry {
+    /// Records an error for `path`
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the filesystem object that failed
+    ///
+    /// * `errno` - The OS error number, if there is one
+    ///
+    pub fn record(&self, path: &Path, errno: Option<i32>) {
+        THREAD_ERRORS.with(|n| n.set(n.get() + 1));
+        let mut groups = lock(&self.groups);
+        groups
+            .entry(errno)
+            .and_modify(|g| g.count += 1)
+            .or_insert_with(|| ErrnoGroup {
+                count: 1,
+                example: path.to_path_buf(),
+            });
+    }
+
+    /// Returns the total number of errors recorded
+    pub fn total(&self) -> u64 {
+        lock(&self.groups).values().map(|g| g.count).sum()
+    }
+
+    /// Returns the exit status for a run that completed, depending on whether any errors occurred
+    pub fn exit_code(&self) -> ExitCode {
+        match self.total() {
+            0 => ExitCode::from(EXIT_CLEAN),
+            _ => ExitCode::from(EXIT_WITH_ERRORS),
+        }
+    }
+
+    /// Prints the errors grouped by errno
+    ///
+    /// # Arguments
+    ///
+    /// * `output` - Output format, JSON Lines prints the summary as a single object
+    ///
+    pub fn print_summary(&self, output: OutputFormat) {
+        let groups = lock(&self.groups);
+        let total: u64 = groups.values().map(|g| g.count).sum();
+
+        if output == OutputFormat::Jsonl {
+            let by_errno: Vec<Value> = groups
+                .iter()
+                .map(|(errno, g)| {
+                    let mut obj = Map::new();
+                    obj.insert("errno".into(), json!(errno));
+                    obj.insert("name".into(), json!(errno_name(*errno)));
+                    obj.insert("count".into(), json!(g.count));
+                    insert_path(&mut obj, "example", &g.example);
+                    Value::Object(obj)
+                })
+                .collect();
+            println!(
+                "{}",
+                json!({ "kind": "errors", "total": total, "by_errno": by_errno })
+            );
+            return;
+        }
+
+        if total == 0 {
+            return;
+        }
+        println!();
+        println!("Errors ({total})");
+        for (errno, g) in groups.iter() {
+            println!(
+                "  {:<40}{:>12}  e.g. {}",
+                errno_name(*errno),
+                g.count,
+                escape_path(&g.example)
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn errno_of_finds_wrapped_errors() {
+        let enoent = Errno::ENOENT as i32;
+        let io_err: Result<(), io::Error> = Err(io::Error::from_raw_os_error(enoent));
+        let e = io_err.context("/data -> Failed").unwrap_err();
+        assert_eq!(errno_of(&e), Some(enoent));
+
+        let nix_err: Result<(), Errno> = Err(Errno::EPERM);
+        let e = nix_err.context("/data -> Failed").unwrap_err();
+        assert_eq!(errno_of(&e), Some(Errno::EPERM as i32));
+
+        assert_eq!(errno_of(&anyhow::anyhow!("no errno here")), None);
+    }
+
+    #[test]
+    fn registry_groups_by_errno() {
+        let registry = ErrorRegistry::default();
+        registry.record(Path::new("/a"), Some(Errno::EPERM as i32));
+        registry.record(Path::new("/b"), Some(Errno::EPERM as i32));
+        registry.record(Path::new("/c"), None);
+        assert_eq!(registry.total(), 3);
+        let groups = registry.groups.lock().unwrap();
+        assert_eq!(groups[&Some(Errno::EPERM as i32)].count, 2);
+        assert_eq!(groups[&Some(Errno::EPERM as i32)].example, Path::new("/a"));
+    }
+}
@@ -0,0 +1,174 @@
+use crate::acl::perm_to_string;
+use crate::types::{AclType, PermissionType};
+use crate::util::insert_path;
+use clap::ValueEnum;
+use serde_json::{json, Map, Value};
+use std::path::Path;
+
+/// How operations are reported on stdout
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable messages, controlled by `--verbose`
+    Text,
+    /// One JSON object per line for every event
+    Jsonl,
+}
+
+/// What happened to a path
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    /// The path was processed
+    Scan,
+    /// The path was skipped, see `reason`
+    Skip,
+    /// The owner or group of the path was changed
+    Chown,
+    /// An ACL entry of the path was rewritten
+    Acl,
+    /// A rewritten ACL entry landed on an id that already had an entry,
+    /// `reason` is the conflict policy that resolved it
+    AclConflict,
+    /// The effective permissions of an ACL entry changed, limited by the mask,
+    /// see `old_perm` and `new_perm`
+    AclEffective,
+    /// File capabilities dropped by a chown were written back,
+    /// `old_id` and `new_id` are set if the namespace root uid was remapped
+    Capability,
+    /// Setuid/setgid bits cleared by a chown were put back, see `mode`
+    Mode,
+    /// An extended attribute was restored from the journal, see `xattr`
+    Xattr,
+    /// Something failed, see `error`
+    Error,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Scan => "scan",
+            EventKind::Skip => "skip",
+            EventKind::Chown => "chown",
+            EventKind::Acl => "acl",
+            EventKind::AclConflict => "acl_conflict",
+            EventKind::AclEffective => "acl_effective",
+            EventKind::Capability => "capability",
+            EventKind::Mode => "mode",
+            EventKind::Xattr => "xattr",
+            EventKind::Error => "error",
+        }
+    }
+}
+
+/// A single structured event, written as one line of JSON
+#[derive(Debug)]
+pub struct Event<'a> {
+    pub kind: EventKind,
+    pub path: &'a Path,
+    pub ptype: Option<PermissionType>,
+    pub old_id: Option<u32>,
+    pub new_id: Option<u32>,
+    pub acl_type: Option<AclType>,
+    /// True if the change was only reported, not made
+    pub noop: bool,
+    /// Permission bits of the path, in octal when serialized
+    pub mode: Option<u32>,
+    /// ACL permissions before a change, as `rwx` text when serialized
+    pub old_perm: Option<u32>,
+    /// ACL permissions after a change, as `rwx` text when serialized
+    pub new_perm: Option<u32>,
+    /// Why a path was skipped
+    pub reason: Option<&'a str>,
+    /// Ignore pattern that excluded the path
+    pub pattern: Option<&'a str>,
+    /// Name of the extended attribute for `EventKind::Xattr`
+    pub xattr: Option<&'a str>,
+    /// Error message for `EventKind::Error`
+    pub error: Option<&'a str>,
+}
+
+impl<'a> Event<'a> {
+    /// Returns an event with only the kind and path set
+    pub fn new(kind: EventKind, path: &'a Path) -> Self {
+        Event {
+            kind,
+            path,
+            ptype: None,
+            old_id: None,
+            new_id: None,
+            acl_type: None,
+            noop: false,
+            mode: None,
+            old_perm: None,
+            new_perm: None,
+            reason: None,
+            pattern: None,
+            xattr: None,
+            error: None,
+        }
+    }
+
+    /// Serializes the event as a single line of JSON, leaving out unset fields
+    pub fn to_json(&self) -> String {
+        let mut obj = Map::new();
+        obj.insert("kind".into(), json!(self.kind.as_str()));
+        insert_path(&mut obj, "path", self.path);
+        if let Some(ptype) = self.ptype {
+            obj.insert("type".into(), json!(ptype.as_str()));
+        }
+        if let Some(old_id) = self.old_id {
+            obj.insert("old_id".into(), json!(old_id));
+        }
+        if let Some(new_id) = self.new_id {
+            obj.insert("new_id".into(), json!(new_id));
+        }
+        if let Some(acl_type) = self.acl_type {
+            obj.insert("acl_type".into(), json!(acl_type.as_str()));
+        }
+        if self.noop {
+            obj.insert("noop".into(), json!(true));
+        }
+        if let Some(mode) = self.mode {
+            obj.insert("mode".into(), json!(format!("{mode:o}")));
+        }
+        if let Some(perm) = self.old_perm {
+            obj.insert("old_perm".into(), json!(perm_to_string(perm)));
+        }
+        if let Some(perm) = self.new_perm {
+            obj.insert("new_perm".into(), json!(perm_to_string(perm)));
+        }
+        if let Some(reason) = self.reason {
+            obj.insert("reason".into(), json!(reason));
+        }
+        if let Some(pattern) = self.pattern {
+            obj.insert("pattern".into(), json!(pattern));
+        }
+        if let Some(xattr) = self.xattr {
+            obj.insert("xattr".into(), json!(xattr));
+        }
+        if let Some(error) = self.error {
+            obj.insert("error".into(), json!(error));
+        }
+        Value::Object(obj).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_serializes_only_set_fields() {
+        let path = Path::new("/data/\"quoted\"");
+        let event = Event {
+            ptype: Some(PermissionType::Group),
+            old_id: Some(57),
+            new_id: Some(219883),
+            noop: true,
+            ..Event::new(EventKind::Chown, path)
+        };
+        assert_eq!(
+            event.to_json(),
+            r#"{"kind":"chown","new_id":219883,"noop":true,"old_id":57,"path":"/data/\"quoted\"","type":"group"}"#
+        );
+    }
+}
@@ -1,234 +1,369 @@
 use crate::acl;
+use crate::capability::{self, CAPABILITY_XATTR};
 use crate::ctx::Ctx;
+use crate::events::{Event, EventKind};
+use crate::fsobject::{get_xattr, proc_path, set_xattr, FsObject};
 use crate::types::PermissionType;
-use anyhow::{bail, Result};
-use file_owner::PathExt;
+use crate::util::escape_path;
+use anyhow::{Context, Result};
+use nix::dir::Dir;
+use nix::libc;
+use nix::sys::stat::{fchmodat, fstat, FchmodatFlags, Mode};
 use nix::unistd::FchownatFlags;
 use nix::unistd::{fchownat, Gid, Uid};
-use std::fs;
-use std::fs::Metadata;
-use std::fs::ReadDir;
-use std::os::linux::fs::MetadataExt;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 
-/// This represents a single File Permission change operation
-pub struct PermissionOperation {
-    /// Either a User permission, or Group permission
-    ptype: PermissionType,
+/// Mode bits the kernel clears on chown
+const SETID_BITS: u32 = libc::S_ISUID | libc::S_ISGID;
+
+/// A single id change, either a uid or a gid
+#[derive(Debug, Clone, Copy)]
+pub struct IdChange {
     /// The current id of the permission
-    current_id: u32,
+    pub current_id: u32,
     /// The new id for the permission
-    new_id: u32,
+    pub new_id: u32,
+}
+
+/// This represents a single File Permission change operation,
+/// the uid and gid are changed together in one call
+pub struct PermissionOperation {
+    /// Change to the owning user, if any
+    pub uid: Option<IdChange>,
+    /// Change to the owning group, if any
+    pub gid: Option<IdChange>,
+    /// Device the object lives on, used to verify the object when undoing
+    pub dev: u64,
+    /// Inode of the object, used to verify the object when undoing
+    pub ino: u64,
     /// Path to the object
-    path: PathBuf,
+    pub path: PathBuf,
 }
 
-/// Gets a listing of file objects
-///
-/// # Arguments
-///
-/// * `path` - Path to the directory
-///
-fn get_directory_listing(path: &Path) -> Result<ReadDir, anyhow::Error> {
-    let dir_listing = match fs::read_dir(path) {
-        Ok(f) => f,
-        Err(e) => {
-            bail!(
-                "{} -> Failed to get directory listing, error: {}",
-                path.display(),
-                e
-            );
-        }
-    };
-    Ok(dir_listing)
+impl PermissionOperation {
+    /// Returns each change in the operation with its permission type, user first
+    pub fn changes(&self) -> impl Iterator<Item = (PermissionType, IdChange)> {
+        let uid = self.uid.map(|c| (PermissionType::User, c));
+        let gid = self.gid.map(|c| (PermissionType::Group, c));
+        uid.into_iter().chain(gid)
+    }
 }
 
-/// Returns a list of paths from the given directory listing
+/// Returns the names of the entries in an open directory, without `.` and `..`
 ///
 /// # Arguments
 ///
-/// * `file_listing` - `ReadDir` object returned from calling `fs::read_dir()`
+/// * `ctx` - Context object used throughout the application
+///
+/// * `dir` - Directory opened with `FsObject::open_dir`
 ///
-pub fn parse_file_listing(file_listing: ReadDir) -> Vec<PathBuf> {
-    let mut outfiles: Vec<PathBuf> = vec![];
-    for file in file_listing {
-        match file {
-            Ok(de) => outfiles.push(de.path()),
+/// * `path` - Path to the directory
+///
+pub fn get_children_names(ctx: &Ctx, dir: &mut Dir, path: &Path) -> Vec<OsString> {
+    let mut names: Vec<OsString> = vec![];
+    for entry in dir.iter() {
+        match entry {
+            Ok(de) => {
+                let name = de.file_name().to_bytes();
+                if name == b"." || name == b".." {
+                    continue;
+                }
+                names.push(OsStr::from_bytes(name).to_os_string());
+            }
             Err(e) => {
-                eprintln!("Error reading file entry: {e}");
-                continue;
+                let e = anyhow::Error::new(e)
+                    .context(format!("{} -> Error reading file entry", escape_path(path)));
+                ctx.error(path, &e);
+                // nix retries readdir on the next call, a persistent error would never end,
+                // the directory stays incomplete for --resume through the reported error
+                break;
             }
         };
     }
-    outfiles
+    names
 }
 
-/// Returns a list of child paths for a given parent path
+/// Uses a `PermissionOperation` to apply settings to a filesystem object.
 ///
 /// # Arguments
 ///
-/// * `path` - Path to the parent directory
+/// * `ctx` - Context object used throughout the application
+///
+/// * `obj` - The object to change
 ///
-pub fn get_children_paths(path: &Path) -> Result<Vec<PathBuf>, anyhow::Error> {
-    let fl = get_directory_listing(path)?;
-    let files = parse_file_listing(fl);
-    Ok(files)
+/// * `perm_op` - `PermissionOperation` object used to signify a permission change
+///
+fn set_file_permission(ctx: &Ctx, obj: &FsObject, perm_op: &PermissionOperation) {
+    let vp = &ctx.verbose_printer;
+    for (ptype, change) in perm_op.changes() {
+        vp.print1(format!(
+            "{} -> Found: Changing {} id from {} to {}",
+            escape_path(&perm_op.path),
+            ptype,
+            change.current_id,
+            change.new_id,
+        ));
+    }
+    if ctx.noop {
+        vp.print1(format!(
+            "{} -> NOOP: Not making changes",
+            escape_path(&perm_op.path)
+        ));
+        ctx.stats.add_changed();
+        for (ptype, change) in perm_op.changes() {
+            ctx.stats.add_ownership(ptype, change.current_id);
+            vp.event(chown_event(perm_op, ptype, change, true));
+        }
+        return;
+    }
+    // the kernel drops file capabilities on chown, read them first to write them back after
+    let capability = match read_capability(obj) {
+        Ok(c) => c,
+        Err(e) => {
+            ctx.error(&perm_op.path, &e);
+            return;
+        }
+    };
+    if let Some(journal) = &ctx.journal {
+        if let Some((_, value)) = &capability {
+            let res = journal.record_xattr(
+                &perm_op.path,
+                CAPABILITY_XATTR,
+                perm_op.dev,
+                perm_op.ino,
+                value,
+            );
+            if let Err(e) = res {
+                ctx.error(&perm_op.path, &e);
+                return;
+            }
+        }
+        if let Err(e) = journal.record_chown(perm_op) {
+            ctx.error(&perm_op.path, &e);
+            return;
+        }
+    }
+    match chown_object(obj, perm_op) {
+        Ok(_) => {
+            ctx.stats.add_changed();
+            for (ptype, change) in perm_op.changes() {
+                ctx.stats.add_ownership(ptype, change.current_id);
+                vp.event(chown_event(perm_op, ptype, change, false));
+            }
+            if let Some((fd, value)) = capability {
+                restore_capability(ctx, obj, &fd, &value);
+            }
+            restore_setid_bits(ctx, obj);
+        }
+        Err(e) => ctx.error(&perm_op.path, &e),
+    }
 }
 
-/// Returns the metadata for a file
+/// Returns the `security.capability` value of a regular file, if it has one,
+/// along with the `O_PATH` handle it was read through to write it back later
+fn read_capability(obj: &FsObject) -> Result<Option<(OwnedFd, Vec<u8>)>> {
+    if !obj.is_file() {
+        return Ok(None);
+    }
+    let fd = obj.open_path()?;
+    // no xattr support at all means no capabilities either, get_xattr returns None then
+    match get_xattr(&fd, CAPABILITY_XATTR) {
+        Ok(value) => Ok(value.map(|v| (fd, v))),
+        Err(e) => Err(anyhow::Error::new(e).context(format!(
+            "{} -> Failed to read file capabilities",
+            escape_path(obj.path)
+        ))),
+    }
+}
+
+/// Writes the file capabilities back after a chown, with a v3 namespace root uid
+/// rewritten through the uid map
 ///
 /// # Arguments
 ///
-/// * `path` - Path to the object
+/// * `ctx` - Context object used throughout the application
 ///
-fn get_file_metadata(path: &Path) -> Result<Metadata, anyhow::Error> {
-    let fm: Metadata;
-    match path.is_symlink() {
-        true => {
-            fm = match fs::symlink_metadata(path) {
-                Ok(fm) => fm,
-                Err(e) => {
-                    bail!("{} -> Failed to parse file metadata: {e}", path.display());
-                }
-            };
-        }
-        false => {
-            fm = match path.metadata() {
-                Ok(fm) => fm,
-                Err(e) => {
-                    bail!("{} -> Failed to parse file metadata: {e}", path.display());
-                }
-            };
-        }
+/// * `obj` - The object that was changed
+///
+/// * `fd` - Handle the capabilities were read through, see `read_capability`
+///
+/// * `value` - The `security.capability` value from before the change
+///
+fn restore_capability(ctx: &Ctx, obj: &FsObject, fd: &OwnedFd, value: &[u8]) {
+    let vp = &ctx.verbose_printer;
+    let remapped = capability::remap_rootid(value, &ctx.uidmap);
+    let new_value = match &remapped {
+        Some((_, _, v)) => v.as_slice(),
+        None => value,
+    };
+    if let Err(e) = set_xattr(fd, CAPABILITY_XATTR, new_value) {
+        let e = anyhow::Error::new(e).context(format!(
+            "{} -> Failed to restore file capabilities",
+            escape_path(obj.path)
+        ));
+        ctx.error(obj.path, &e);
+        return;
     }
-    Ok(fm)
+    match remapped {
+        Some((old, new, _)) => vp.print1(format!(
+            "{} -> Restored file capabilities, namespace root uid {} -> {}",
+            escape_path(obj.path),
+            old,
+            new
+        )),
+        None => vp.print1(format!(
+            "{} -> Restored file capabilities",
+            escape_path(obj.path)
+        )),
+    }
+    ctx.stats.add_capability_restored();
+    vp.event(Event {
+        old_id: remapped.as_ref().map(|(old, _, _)| *old),
+        new_id: remapped.as_ref().map(|(_, new, _)| *new),
+        ..Event::new(EventKind::Capability, obj.path)
+    });
 }
 
-/// Uses a `PermissionOperation` to apply settings to a filesystem object.
+/// Puts back the setuid/setgid bits the kernel cleared when the owner of the object changed,
+/// unless the user asked for them to be dropped
 ///
 /// # Arguments
 ///
 /// * `ctx` - Context object used throughout the application
 ///
-/// * `perm_op` - `PermissionOperation` object used to signify a permission change
+/// * `obj` - The object that was changed, with its metadata from before the change
 ///
-fn set_file_permission(ctx: &Ctx, perm_op: &PermissionOperation) {
+fn restore_setid_bits(ctx: &Ctx, obj: &FsObject) {
+    if obj.stat.st_mode & SETID_BITS == 0 || obj.is_symlink() {
+        return;
+    }
     let vp = &ctx.verbose_printer;
-    vp.print1(format!(
-        "{} -> Found: Changing {} id from {} to {}",
-        perm_op.path.display(),
-        perm_op.ptype.to_string(),
-        perm_op.current_id,
-        perm_op.new_id,
-    ));
-    if ctx.noop {
+    if ctx.drop_setid {
         vp.print1(format!(
-            "{} -> NOOP: Not making changes",
-            perm_op.path.display()
+            "{} -> Not restoring setuid/setgid bits",
+            escape_path(obj.path)
         ));
         return;
     }
-    match &perm_op.ptype {
-        PermissionType::User => match perm_op.path.is_symlink() {
-            true => set_user_file_permission_on_symlink(perm_op),
-            false => set_user_file_permission_on_file(perm_op),
-        },
-        PermissionType::Group => match perm_op.path.is_symlink() {
-            true => set_group_file_permission_on_symlink(perm_op),
-            false => set_group_file_permission_on_file(perm_op),
-        },
+    let res = obj
+        .open_path()
+        .and_then(|fd| restore_setid(&fd, obj.stat.st_mode));
+    match res {
+        Ok(true) => {
+            let mode = obj.stat.st_mode & 0o7777;
+            vp.print1(format!(
+                "{} -> Restored setuid/setgid bits, mode {:o}",
+                escape_path(obj.path),
+                mode
+            ));
+            ctx.stats.add_setid_restored();
+            vp.event(Event {
+                mode: Some(mode),
+                ..Event::new(EventKind::Mode, obj.path)
+            });
+        }
+        Ok(false) => (),
+        Err(e) => ctx.error(
+            obj.path,
+            &e.context(format!(
+                "{} -> Failed to restore setuid/setgid bits",
+                escape_path(obj.path)
+            )),
+        ),
     }
 }
 
-fn set_user_file_permission_on_file(perm_op: &PermissionOperation) {
-    if let Err(e) = perm_op.path.set_owner(perm_op.new_id) {
-        eprintln!(
-            "{} -> Failed to set uid, error: {}",
-            perm_op.path.display(),
-            e
-        )
-    }
-}
-fn set_group_file_permission_on_file(perm_op: &PermissionOperation) {
-    if let Err(e) = perm_op.path.set_group(perm_op.new_id) {
-        eprintln!(
-            "{} -> Failed to set gid, error: {}",
-            perm_op.path.display(),
-            e
-        )
+/// Restores the setuid/setgid bits of `mode` on the object behind `fd` if a chown cleared them.
+/// Returns true if the mode had to be changed.
+///
+/// # Arguments
+///
+/// * `fd` - `O_PATH` handle on the object, never a symlink
+///
+/// * `mode` - Mode of the object from before the chown
+///
+pub fn restore_setid(fd: &OwnedFd, mode: u32) -> Result<bool> {
+    let current = fstat(fd.as_raw_fd())?.st_mode;
+    if current & SETID_BITS == mode & SETID_BITS {
+        return Ok(false);
     }
-}
-fn set_user_file_permission_on_symlink(perm_op: &PermissionOperation) {
-    if let Err(e) = fchownat(
+    // an O_PATH handle can't be chmod'ed directly, but its procfs link can
+    fchmodat(
         None,
-        &perm_op.path,
-        Some(Uid::from(perm_op.new_id)),
-        None,
-        FchownatFlags::NoFollowSymlink,
-    ) {
-        eprintln!(
-            "{} -> Failed to set uid, error: {}",
-            perm_op.path.display(),
-            e
-        )
+        &proc_path(fd),
+        Mode::from_bits_truncate(mode & 0o7777),
+        FchmodatFlags::FollowSymlink,
+    )?;
+    Ok(true)
+}
+
+/// Returns the `EventKind::Chown` event for one change of a `PermissionOperation`
+fn chown_event(
+    perm_op: &PermissionOperation,
+    ptype: PermissionType,
+    change: IdChange,
+    noop: bool,
+) -> Event<'_> {
+    Event {
+        ptype: Some(ptype),
+        old_id: Some(change.current_id),
+        new_id: Some(change.new_id),
+        noop,
+        ..Event::new(EventKind::Chown, &perm_op.path)
     }
 }
-fn set_group_file_permission_on_symlink(perm_op: &PermissionOperation) {
-    if let Err(e) = fchownat(
-        None,
-        &perm_op.path,
-        None,
-        Some(Gid::from(perm_op.new_id)),
+
+/// Applies the uid and gid changes with a single `fchownat` call relative to the
+/// object's directory, never following symlinks so a link is changed rather than its target
+fn chown_object(obj: &FsObject, perm_op: &PermissionOperation) -> Result<()> {
+    let uid = perm_op.uid.map(|c| Uid::from(c.new_id));
+    let gid = perm_op.gid.map(|c| Gid::from(c.new_id));
+    fchownat(
+        Some(obj.dirfd),
+        obj.name,
+        uid,
+        gid,
         FchownatFlags::NoFollowSymlink,
-    ) {
-        eprintln!(
-            "{} -> Failed to set gid, error: {}",
-            perm_op.path.display(),
-            e
-        )
-    }
+    )
+    .with_context(|| format!("{} -> Failed to set owner", escape_path(&perm_op.path)))?;
+    Ok(())
 }
 
-/// Inspects the provided file metadata and optionally returns
+/// Inspects the metadata of the object and optionally returns
 /// a `PermissionOperation` if a change is needed.
 ///
 /// # Arguments
 ///
 /// * `ctx` - Context object used throughout the application
 ///
-/// * `metadata` - File metadata
-///
-/// * `path` - Path to the object
+/// * `obj` - The object to inspect
 ///
-fn get_permission_operation(
-    ctx: &Ctx,
-    metadata: &Metadata,
-    path: &Path,
-    ptype: PermissionType,
-) -> Option<PermissionOperation> {
-    let current_id = match ptype {
-        PermissionType::User => metadata.st_uid(),
-        PermissionType::Group => metadata.st_gid(),
-    };
-    let new_id = match ptype {
-        PermissionType::User => match ctx.uidmap.get(&current_id) {
-            Some(new_id) => *new_id,
-            None => return None,
-        },
-        PermissionType::Group => match ctx.gidmap.get(&current_id) {
-            Some(new_id) => *new_id,
-            None => return None,
-        },
+fn get_permission_operation(ctx: &Ctx, obj: &FsObject) -> Option<PermissionOperation> {
+    let change = |idmap: &HashMap<u32, u32>, current_id: u32| {
+        idmap.get(&current_id).map(|new_id| IdChange {
+            current_id,
+            new_id: *new_id,
+        })
     };
-    return Some(PermissionOperation {
-        ptype,
-        current_id,
-        new_id,
-        path: path.to_path_buf(),
-    });
+    let uid = change(&ctx.uidmap, obj.stat.st_uid);
+    let gid = change(&ctx.gidmap, obj.stat.st_gid);
+    if uid.is_none() && gid.is_none() {
+        return None;
+    }
+    Some(PermissionOperation {
+        uid,
+        gid,
+        dev: obj.stat.st_dev,
+        ino: obj.stat.st_ino,
+        path: obj.path.to_path_buf(),
+    })
 }
 
-/// Checks the object at `path` against the user and group maps.
+/// Checks the object against the user and group maps.
 /// If it finds that the object has a matching uid or gid to the keys in
 /// the respective maps, it will replace those uid/gids with the values in the map.
 ///
@@ -236,30 +371,18 @@ fn get_permission_operation(
 ///
 /// * `ctx` - Context object used throughout the application
 ///
-/// * `path` - Path to the object
+/// * `obj` - The object to update
 ///
-fn update_file_permissions(ctx: &Ctx, path: &Path) -> Result<()> {
+fn update_file_permissions(ctx: &Ctx, obj: &FsObject) {
     let vp = &ctx.verbose_printer;
-    vp.print1(format!("{} -> Processing file permissions", path.display()));
-    let fm = get_file_metadata(path.as_ref())?;
-
-    let mut ops: Vec<PermissionOperation> = vec![];
-    if !ctx.uidmap.is_empty() {
-        if let Some(po) = get_permission_operation(&ctx, &fm, path, PermissionType::User) {
-            ops.push(po);
-        }
-    }
-    if !ctx.gidmap.is_empty() {
-        if let Some(po) = get_permission_operation(&ctx, &fm, path, PermissionType::Group) {
-            ops.push(po)
-        }
-    }
+    vp.print1(format!(
+        "{} -> Processing file permissions",
+        escape_path(obj.path)
+    ));
 
-    for po in ops {
-        set_file_permission(&ctx, &po);
+    if let Some(po) = get_permission_operation(ctx, obj) {
+        set_file_permission(ctx, obj, &po);
     }
-
-    Ok(())
 }
 
 /// The primary function for processing paths.
@@ -269,20 +392,14 @@ fn update_file_permissions(ctx: &Ctx, path: &Path) -> Result<()> {
 /// First, the file permissions are updated. Then if the user passed the ACL flag,
 /// update the ACLs.
 ///
-pub fn process_path(ctx: &Ctx, path: &Path) {
+pub fn process_path(ctx: &Ctx, obj: &FsObject) {
     // Update unix permissions
     if !ctx.skip_permissions {
-        match update_file_permissions(&ctx, path) {
-            Ok(_) => (),
-            Err(e) => {
-                eprintln!("{e}");
-                return;
-            }
-        };
+        update_file_permissions(ctx, obj);
     }
 
     // Modify the posix ACLs if flag was provided
     if !ctx.skip_acls {
-        acl::update_acl(&ctx, &path);
+        acl::update_acl(ctx, obj);
     }
 }
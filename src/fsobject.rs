@@ -0,0 +1,254 @@
+use crate::util::escape_path;
+use anyhow::{bail, Context, Result};
+use nix::dir::Dir;
+use nix::fcntl::{openat, AtFlags, OFlag};
+use nix::libc;
+use nix::sys::resource::{getrlimit, setrlimit, Resource};
+use nix::sys::stat::{fstat, fstatat, FileStat, Mode, SFlag};
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// A filesystem object addressed by name relative to an open directory.
+///
+/// Every stat, chown and ACL operation goes through `dirfd` without following symlinks,
+/// so the full path is never resolved again once the walk has started.
+/// The full path is only kept for messages and the journal.
+pub struct FsObject<'a> {
+    /// Directory `name` is relative to, `AT_FDCWD` for base paths
+    pub dirfd: RawFd,
+    /// Name of the object inside `dirfd`, or the base path itself
+    pub name: &'a Path,
+    /// Full path to the object
+    pub path: &'a Path,
+    /// Metadata of the object itself, symlinks are not followed
+    pub stat: FileStat,
+}
+
+impl<'a> FsObject<'a> {
+    /// Stats `name` relative to `dirfd` and returns the object
+    ///
+    /// # Arguments
+    ///
+    /// * `dirfd` - Open directory `name` is relative to, `AT_FDCWD` for base paths
+    ///
+    /// * `name` - Name of the object inside `dirfd`
+    ///
+    /// * `path` - Full path to the object, for messages
+    ///
+    pub fn stat_at(dirfd: RawFd, name: &'a Path, path: &'a Path) -> Result<Self> {
+        let stat = fstatat(dirfd, name, AtFlags::AT_SYMLINK_NOFOLLOW)
+            .with_context(|| format!("{} -> Failed to parse file metadata", escape_path(path)))?;
+        Ok(FsObject {
+            dirfd,
+            name,
+            path,
+            stat,
+        })
+    }
+
+    /// Returns the object for a base path given on the command line,
+    /// relative paths are resolved against the current directory
+    pub fn base(path: &'a Path) -> Result<Self> {
+        FsObject::stat_at(libc::AT_FDCWD, path, path)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        SFlag::from_bits_truncate(self.stat.st_mode) & SFlag::S_IFMT == SFlag::S_IFDIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        SFlag::from_bits_truncate(self.stat.st_mode) & SFlag::S_IFMT == SFlag::S_IFREG
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        SFlag::from_bits_truncate(self.stat.st_mode) & SFlag::S_IFMT == SFlag::S_IFLNK
+    }
+
+    /// Opens the directory for listing and for addressing its children.
+    /// Fails if the object was replaced since it was stat'ed.
+    pub fn open_dir(&self) -> Result<Dir> {
+        let flags = OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+        let dir = Dir::openat(self.dirfd, self.name, flags, Mode::empty())
+            .with_context(|| format!("{} -> Failed to open directory", escape_path(self.path)))?;
+        self.check_same(dir.as_raw_fd())?;
+        Ok(dir)
+    }
+
+    /// Opens an `O_PATH` handle on the object itself, without following symlinks.
+    /// Fails if the object was replaced since it was stat'ed.
+    pub fn open_path(&self) -> Result<OwnedFd> {
+        self.open(OFlag::O_PATH | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC)
+    }
+
+    fn open(&self, flags: OFlag) -> Result<OwnedFd> {
+        let fd = openat(self.dirfd, self.name, flags, Mode::empty())
+            .with_context(|| format!("{} -> Failed to open", escape_path(self.path)))?;
+        // SAFETY: openat just returned this descriptor and nothing else owns it
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        self.check_same(fd.as_raw_fd())?;
+        Ok(fd)
+    }
+
+    /// Makes sure `fd` still refers to the object that was stat'ed
+    fn check_same(&self, fd: RawFd) -> Result<()> {
+        let st = fstat(fd).with_context(|| {
+            format!(
+                "{} -> Failed to parse file metadata",
+                escape_path(self.path)
+            )
+        })?;
+        if st.st_dev != self.stat.st_dev || st.st_ino != self.stat.st_ino {
+            bail!(
+                "{} -> Object was replaced during the run, skipping",
+                escape_path(self.path)
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Raises the soft limit of open files to the hard limit.
+/// Every directory stays open while its children are processed in parallel,
+/// so deep trees need up to depth × threads descriptors at once, more than
+/// the usual soft limit of 1024. Failing to raise it is not an error.
+pub fn raise_open_files_limit() {
+    if let Ok((soft, hard)) = getrlimit(Resource::RLIMIT_NOFILE) {
+        if soft < hard {
+            let _ = setrlimit(Resource::RLIMIT_NOFILE, hard, hard);
+        }
+    }
+}
+
+/// Returns a path that reaches the object behind `fd` through procfs,
+/// for APIs that only take paths, such as reading and writing ACLs.
+/// /proc has to be mounted.
+pub fn proc_path(fd: &OwnedFd) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", fd.as_raw_fd()))
+}
+
+/// Returns the names of the extended attributes of the object behind `fd`,
+/// empty if the filesystem doesn't support them. See `set_xattr` for why this
+/// doesn't go through the `xattr` crate.
+///
+/// # Arguments
+///
+/// * `fd` - Handle on the object, see `FsObject::open_path`
+///
+pub fn list_xattrs(fd: &OwnedFd) -> io::Result<Vec<Vec<u8>>> {
+    let path = CString::new(proc_path(fd).as_os_str().as_bytes())?;
+    loop {
+        // SAFETY: path is NUL terminated, a zero size only queries the length
+        let len = unsafe { libc::listxattr(path.as_ptr(), std::ptr::null_mut(), 0) };
+        if len < 0 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::EOPNOTSUPP) => Ok(vec![]),
+                _ => Err(e),
+            };
+        }
+        let mut list = vec![0u8; len as usize];
+        // SAFETY: list is valid for list.len() bytes
+        let ret = unsafe {
+            libc::listxattr(
+                path.as_ptr(),
+                list.as_mut_ptr() as *mut libc::c_char,
+                list.len(),
+            )
+        };
+        if ret >= 0 {
+            list.truncate(ret as usize);
+            return Ok(list
+                .split(|b| *b == 0)
+                .filter(|n| !n.is_empty())
+                .map(|n| n.to_vec())
+                .collect());
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            // an attribute was added between the two calls, ask again
+            Some(libc::ERANGE) => continue,
+            _ => return Err(e),
+        }
+    }
+}
+
+/// Returns an extended attribute of the object behind `fd`, None if it isn't set
+/// or the filesystem doesn't support its namespace. See `set_xattr` for why this
+/// doesn't go through the `xattr` crate.
+///
+/// # Arguments
+///
+/// * `fd` - Handle on the object, see `FsObject::open_path`
+///
+/// * `name` - Name of the extended attribute
+///
+pub fn get_xattr(fd: &OwnedFd, name: &str) -> io::Result<Option<Vec<u8>>> {
+    let path = CString::new(proc_path(fd).as_os_str().as_bytes())?;
+    let name = CString::new(name)?;
+    loop {
+        // SAFETY: both strings are NUL terminated, a zero size only queries the length
+        let len = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+        if len < 0 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::ENODATA) | Some(libc::EOPNOTSUPP) => Ok(None),
+                _ => Err(e),
+            };
+        }
+        let mut value = vec![0u8; len as usize];
+        // SAFETY: value is valid for value.len() bytes
+        let ret = unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_mut_ptr() as *mut libc::c_void,
+                value.len(),
+            )
+        };
+        if ret >= 0 {
+            value.truncate(ret as usize);
+            return Ok(Some(value));
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            // the attribute grew between the two calls, ask again
+            Some(libc::ERANGE) => continue,
+            Some(libc::ENODATA) => return Ok(None),
+            _ => return Err(e),
+        }
+    }
+}
+
+/// Sets an extended attribute on the object behind `fd`.
+/// The `xattr` crate only offers the calls that don't follow symlinks,
+/// which act on the procfs link itself, so this uses `setxattr` directly.
+///
+/// # Arguments
+///
+/// * `fd` - Handle on the object, see `FsObject::open_path`
+///
+/// * `name` - Name of the extended attribute
+///
+/// * `value` - Raw value to set
+///
+pub fn set_xattr(fd: &OwnedFd, name: &str, value: &[u8]) -> io::Result<()> {
+    let path = CString::new(proc_path(fd).as_os_str().as_bytes())?;
+    let name = CString::new(name)?;
+    // SAFETY: both strings are NUL terminated and value is valid for value.len() bytes
+    let ret = unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    match ret {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
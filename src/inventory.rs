@@ -0,0 +1,213 @@
+use crate::acl;
+use crate::ctx::Ctx;
+use crate::events::OutputFormat;
+use crate::fsobject::FsObject;
+use crate::types::{AclType, PermissionType};
+use crate::util::{escape_path, lock};
+use nix::unistd::{Gid, Group, Uid, User};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// How many example paths are kept for every id
+const MAX_EXAMPLES: usize = 3;
+
+/// How much of the tree a single uid or gid accounts for
+#[derive(Debug, Default)]
+struct IdUsage {
+    /// Number of objects owned by the id, as owner or group
+    files: u64,
+    /// Total size of the objects owned by the id
+    bytes: u64,
+    /// Number of objects with an access ACL entry for the id
+    access_acl: u64,
+    /// Number of directories with a default ACL entry for the id
+    default_acl: u64,
+    /// First few paths found referencing the id, to locate orphans
+    examples: Vec<PathBuf>,
+}
+
+impl IdUsage {
+    fn add_example(&mut self, path: &Path) {
+        if self.examples.len() < MAX_EXAMPLES && !self.examples.iter().any(|p| p == path) {
+            self.examples.push(path.to_path_buf());
+        }
+    }
+}
+
+/// Returns the account name of an id through NSS, None if it doesn't resolve.
+/// A failed lookup is reported and treated as resolved, so an unreachable
+/// directory service doesn't make every id look orphaned.
+///
+/// # Arguments
+///
+/// * `ptype` - Whether `id` is a uid or a gid
+///
+/// * `id` - The id to look up
+///
+fn account_name(ptype: PermissionType, id: u32) -> Option<String> {
+    let name = match ptype {
+        PermissionType::User => User::from_uid(Uid::from_raw(id)).map(|u| u.map(|u| u.name)),
+        PermissionType::Group => Group::from_gid(Gid::from_raw(id)).map(|g| g.map(|g| g.name)),
+    };
+    match name {
+        Ok(n) => n,
+        Err(e) => {
+            eprintln!("{id} -> Failed to look up account: {e}");
+            Some(String::from("?"))
+        }
+    }
+}
+
+/// Histogram of every uid and gid found while scanning a tree,
+/// used to plan a migration before any mapping exists
+#[derive(Debug, Default)]
+pub struct Inventory {
+    uids: Mutex<BTreeMap<u32, IdUsage>>,
+    gids: Mutex<BTreeMap<u32, IdUsage>>,
+}
+
+impl Inventory {
+    fn usage(&self, ptype: PermissionType) -> std::sync::MutexGuard<'_, BTreeMap<u32, IdUsage>> {
+        let ids = match ptype {
+            PermissionType::User => &self.uids,
+            PermissionType::Group => &self.gids,
+        };
+        lock(ids)
+    }
+
+    /// Records the owner, group and ACL entries of a single object
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - Context object used throughout the application
+    ///
+    /// * `obj` - The filesystem object
+    ///
+    pub fn record(&self, ctx: &Ctx, obj: &FsObject) {
+        let size = obj.stat.st_size as u64;
+        for (ptype, id) in [
+            (PermissionType::User, obj.stat.st_uid),
+            (PermissionType::Group, obj.stat.st_gid),
+        ] {
+            let mut usage = self.usage(ptype);
+            let u = usage.entry(id).or_default();
+            u.files += 1;
+            u.bytes += size;
+            u.add_example(obj.path);
+        }
+
+        if ctx.skip_acls {
+            return;
+        }
+        for (atype, ptype, id) in acl::acl_ids(ctx, obj) {
+            let mut usage = self.usage(ptype);
+            let u = usage.entry(id).or_default();
+            match atype {
+                AclType::Access => u.access_acl += 1,
+                AclType::Default => u.default_acl += 1,
+            }
+            u.add_example(obj.path);
+        }
+    }
+
+    /// Prints the histogram, uids first, each sorted by id, followed by
+    /// the orphaned ids which don't resolve to an account
+    ///
+    /// # Arguments
+    ///
+    /// * `output` - Output format, JSON Lines prints one object per id
+    ///
+    pub fn print_summary(&self, output: OutputFormat) {
+        if output == OutputFormat::Text {
+            println!();
+            println!(
+                "  {:<5}{:>12}  {:<16}{:>14}{:>18}{:>12}{:>12}",
+                "type", "id", "name", "files", "bytes", "access", "default"
+            );
+        }
+        let mut orphans = vec![];
+        for (kind, ptype) in [
+            ("uid", PermissionType::User),
+            ("gid", PermissionType::Group),
+        ] {
+            for (id, u) in self.usage(ptype).iter() {
+                let name = account_name(ptype, *id);
+                if name.is_none() {
+                    orphans.push((kind, *id, u.examples.clone()));
+                }
+                match output {
+                    OutputFormat::Jsonl => println!(
+                        "{}",
+                        json!({
+                            "kind": "inventory",
+                            "type": kind,
+                            "id": id,
+                            "name": name,
+                            "files": u.files,
+                            "bytes": u.bytes,
+                            "access_acl": u.access_acl,
+                            "default_acl": u.default_acl,
+                        })
+                    ),
+                    OutputFormat::Text => println!(
+                        "  {:<5}{:>12}  {:<16}{:>14}{:>18}{:>12}{:>12}",
+                        kind,
+                        id,
+                        name.as_deref().unwrap_or("-"),
+                        u.files,
+                        u.bytes,
+                        u.access_acl,
+                        u.default_acl
+                    ),
+                }
+            }
+        }
+
+        if output == OutputFormat::Text && !orphans.is_empty() {
+            println!();
+            println!("  Orphaned ids, not found in the account database:");
+        }
+        for (kind, id, examples) in orphans {
+            match output {
+                OutputFormat::Jsonl => {
+                    // same as `insert_path`, raw bytes are only added if a name needs them
+                    let mut obj = Map::new();
+                    obj.insert("kind".into(), json!("orphan"));
+                    obj.insert("type".into(), json!(kind));
+                    obj.insert("id".into(), json!(id));
+                    let text: Vec<_> = examples.iter().map(|p| p.to_string_lossy()).collect();
+                    obj.insert("examples".into(), json!(text));
+                    if examples.iter().any(|p| p.to_str().is_none()) {
+                        let bytes: Vec<_> =
+                            examples.iter().map(|p| p.as_os_str().as_bytes()).collect();
+                        obj.insert("examples_bytes".into(), json!(bytes));
+                    }
+                    println!("{}", Value::Object(obj));
+                }
+                OutputFormat::Text => {
+                    println!("  {kind} {id}");
+                    for e in &examples {
+                        println!("      {}", escape_path(e));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_a_few_distinct_examples() {
+        let mut u = IdUsage::default();
+        for p in ["/a", "/a", "/b", "/c", "/d"] {
+            u.add_example(Path::new(p));
+        }
+        assert_eq!(u.examples, ["/a", "/b", "/c"].map(PathBuf::from));
+    }
+}
@@ -0,0 +1,602 @@
+use crate::acl;
+use crate::capability;
+use crate::ctx::Ctx;
+use crate::events::{Event, EventKind};
+use crate::files::{self, PermissionOperation};
+use crate::types::{AclType, PermissionType};
+use crate::util::{absolute_path, escape_path, unescape_path};
+use anyhow::{anyhow, bail, Result};
+use nix::libc;
+use posix_acl::ACLEntry;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::linux::fs::MetadataExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Append-only record of every change made to the filesystem.
+///
+/// Each line is written and flushed *before* the change it describes is applied,
+/// so the journal always contains enough information to put things back.
+#[derive(Debug)]
+pub struct Journal {
+    file: Mutex<File>,
+}
+
+/// A single parsed line of the journal
+#[derive(Debug)]
+pub enum JournalRecord {
+    /// An ownership change of a single uid or gid
+    Chown {
+        ptype: PermissionType,
+        dev: u64,
+        ino: u64,
+        current_id: u32,
+        new_id: u32,
+        path: PathBuf,
+    },
+    /// An extended attribute as it was before being rewritten, value hex encoded
+    Xattr {
+        name: String,
+        dev: u64,
+        ino: u64,
+        value: String,
+        path: PathBuf,
+    },
+    /// The full ACL as it was before being rewritten
+    Acl {
+        atype: AclType,
+        dev: u64,
+        ino: u64,
+        entries: String,
+        path: PathBuf,
+    },
+}
+
+impl Journal {
+    /// Opens the journal at `path` for appending, creating it if needed
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the journal file
+    ///
+    pub fn open(path: &Path) -> Result<Journal, anyhow::Error> {
+        let file = match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(f) => f,
+            Err(e) => bail!("{} -> Failed to open journal: {e}", escape_path(path)),
+        };
+        Ok(Journal {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Writes a single line to the journal and flushes it to disk
+    fn append(&self, line: String) -> Result<(), anyhow::Error> {
+        let mut file = match self.file.lock() {
+            Ok(f) => f,
+            Err(_) => bail!("Journal lock poisoned, refusing to continue writing"),
+        };
+        if let Err(e) = file
+            .write_all(line.as_bytes())
+            .and_then(|_| file.sync_data())
+        {
+            bail!("Failed to write journal entry: {e}");
+        }
+        Ok(())
+    }
+
+    /// Records an ownership change before it is applied,
+    /// one line per id change, written together
+    ///
+    /// # Arguments
+    ///
+    /// * `perm_op` - The `PermissionOperation` about to be applied
+    ///
+    pub fn record_chown(&self, perm_op: &PermissionOperation) -> Result<(), anyhow::Error> {
+        let path = absolute_path(&perm_op.path);
+        let mut lines = String::new();
+        for (ptype, change) in perm_op.changes() {
+            let record = JournalRecord::Chown {
+                ptype,
+                dev: perm_op.dev,
+                ino: perm_op.ino,
+                current_id: change.current_id,
+                new_id: change.new_id,
+                path: path.clone(),
+            };
+            lines.push_str(&record.to_line());
+        }
+        self.append(lines)
+    }
+
+    /// Records an extended attribute of `path` before it is rewritten
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the filesystem object
+    ///
+    /// * `name` - Name of the extended attribute
+    ///
+    /// * `dev` - Device the object lives on
+    ///
+    /// * `ino` - Inode of the object
+    ///
+    /// * `value` - Value of the attribute as it currently exists on disk
+    ///
+    pub fn record_xattr(
+        &self,
+        path: &Path,
+        name: &str,
+        dev: u64,
+        ino: u64,
+        value: &[u8],
+    ) -> Result<(), anyhow::Error> {
+        self.append(
+            JournalRecord::Xattr {
+                name: name.to_string(),
+                dev,
+                ino,
+                value: capability::to_hex(value),
+                path: absolute_path(path),
+            }
+            .to_line(),
+        )
+    }
+
+    /// Records the full ACL of `path` before it is rewritten
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the filesystem object
+    ///
+    /// * `atype` - Which ACL is about to be rewritten
+    ///
+    /// * `dev` - Device the object lives on
+    ///
+    /// * `ino` - Inode of the object
+    ///
+    /// * `entries` - Entries of the ACL as it currently exists on disk
+    ///
+    pub fn record_acl(
+        &self,
+        path: &Path,
+        atype: AclType,
+        dev: u64,
+        ino: u64,
+        entries: &[ACLEntry],
+    ) -> Result<(), anyhow::Error> {
+        self.append(
+            JournalRecord::Acl {
+                atype,
+                dev,
+                ino,
+                entries: acl::entries_to_string(entries),
+                path: absolute_path(path),
+            }
+            .to_line(),
+        )
+    }
+}
+
+impl JournalRecord {
+    /// Serializes the record as a single tab separated line, path always last
+    pub fn to_line(&self) -> String {
+        match self {
+            JournalRecord::Chown {
+                ptype,
+                dev,
+                ino,
+                current_id,
+                new_id,
+                path,
+            } => format!(
+                "chown\t{}\t{dev}\t{ino}\t{current_id}\t{new_id}\t{}\n",
+                ptype.as_str(),
+                escape_path(path)
+            ),
+            JournalRecord::Xattr {
+                name,
+                dev,
+                ino,
+                value,
+                path,
+            } => format!(
+                "xattr\t{name}\t{dev}\t{ino}\t{value}\t{}\n",
+                escape_path(path)
+            ),
+            JournalRecord::Acl {
+                atype,
+                dev,
+                ino,
+                entries,
+                path,
+            } => format!(
+                "acl\t{}\t{dev}\t{ino}\t{entries}\t{}\n",
+                atype.as_str(),
+                escape_path(path)
+            ),
+        }
+    }
+
+    /// Parses a single journal line
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - Line without the trailing newline
+    ///
+    pub fn from_line(line: &str) -> Result<JournalRecord, anyhow::Error> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 7 && fields.len() != 6 {
+            bail!("Invalid journal entry: '{line}'");
+        }
+        let dev = parse_field::<u64>(fields[2], line)?;
+        let ino = parse_field::<u64>(fields[3], line)?;
+        let path = unescape_path(fields[fields.len() - 1])?;
+        match (fields[0], fields.len()) {
+            ("chown", 7) => {
+                let ptype = match fields[1] {
+                    "user" => PermissionType::User,
+                    "group" => PermissionType::Group,
+                    o => bail!("Invalid permission type '{o}' in journal entry: '{line}'"),
+                };
+                Ok(JournalRecord::Chown {
+                    ptype,
+                    dev,
+                    ino,
+                    current_id: parse_field::<u32>(fields[4], line)?,
+                    new_id: parse_field::<u32>(fields[5], line)?,
+                    path,
+                })
+            }
+            ("acl", 6) => {
+                let atype = match fields[1] {
+                    "access" => AclType::Access,
+                    "default" => AclType::Default,
+                    o => bail!("Invalid ACL type '{o}' in journal entry: '{line}'"),
+                };
+                Ok(JournalRecord::Acl {
+                    atype,
+                    dev,
+                    ino,
+                    entries: fields[4].to_string(),
+                    path,
+                })
+            }
+            ("xattr", 6) => Ok(JournalRecord::Xattr {
+                name: fields[1].to_string(),
+                dev,
+                ino,
+                value: fields[4].to_string(),
+                path,
+            }),
+            _ => bail!("Invalid journal entry: '{line}'"),
+        }
+    }
+}
+
+/// Parses a numeric journal field, with the whole line in the error message
+fn parse_field<T: std::str::FromStr>(field: &str, line: &str) -> Result<T, anyhow::Error> {
+    match field.parse::<T>() {
+        Ok(v) => Ok(v),
+        Err(_) => bail!("Unable to parse '{field}' in journal entry: '{line}'"),
+    }
+}
+
+/// Reads every record from the journal at `path`, in the order they were written
+///
+/// # Arguments
+///
+/// * `path` - Path to the journal file
+///
+pub fn read_journal(path: &Path) -> Result<Vec<JournalRecord>, anyhow::Error> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) => bail!("{} -> Failed to open journal: {e}", escape_path(path)),
+    };
+    let mut records = vec![];
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = match line {
+            Ok(l) => l,
+            Err(e) => bail!(
+                "{}:{} -> Failed to read journal: {e}",
+                escape_path(path),
+                i + 1
+            ),
+        };
+        if line.is_empty() {
+            continue;
+        }
+        match JournalRecord::from_line(&line) {
+            Ok(r) => records.push(r),
+            Err(e) => bail!("{}:{} -> {e}", escape_path(path), i + 1),
+        }
+    }
+    Ok(records)
+}
+
+/// Changes the owner of the object behind an `O_PATH` handle, a symlink itself rather than its target
+fn chown_handle(fd: &OwnedFd, uid: u32, gid: u32) -> std::io::Result<()> {
+    // SAFETY: the path is an empty NUL terminated string, AT_EMPTY_PATH makes fd the target
+    let ret =
+        unsafe { libc::fchownat(fd.as_raw_fd(), c"".as_ptr(), uid, gid, libc::AT_EMPTY_PATH) };
+    match ret {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+/// Returns true if the object at `path` is still the one the journal recorded
+fn same_object(ctx: &Ctx, path: &Path, dev: u64, ino: u64) -> bool {
+    match fs::symlink_metadata(path) {
+        Ok(fm) => {
+            if fm.st_dev() != dev || fm.st_ino() != ino {
+                let e = anyhow!(
+                    "{} -> Object changed since journal was written (device/inode mismatch), skipping",
+                    escape_path(path)
+                );
+                ctx.error(path, &e);
+                return false;
+            }
+            true
+        }
+        Err(e) => {
+            let e = anyhow::Error::new(e).context(format!(
+                "{} -> Failed to parse file metadata",
+                escape_path(path)
+            ));
+            ctx.error(path, &e);
+            false
+        }
+    }
+}
+
+/// Opens an `O_PATH` handle on the object at `path` and makes sure it is still the one
+/// the journal recorded, returns the handle and the mode of the object.
+/// Changes then go through the handle, so the object can't be swapped after the check.
+fn open_recorded(ctx: &Ctx, path: &Path, dev: u64, ino: u64) -> Option<(OwnedFd, u32)> {
+    let handle = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_PATH | libc::O_NOFOLLOW)
+        .open(path);
+    let (fd, md) = match handle.and_then(|f| Ok((f.metadata()?, f))) {
+        Ok((md, f)) => (OwnedFd::from(f), md),
+        Err(e) => {
+            let e =
+                anyhow::Error::new(e).context(format!("{} -> Failed to open", escape_path(path)));
+            ctx.error(path, &e);
+            return None;
+        }
+    };
+    if md.st_dev() != dev || md.st_ino() != ino {
+        let e = anyhow!(
+            "{} -> Object changed since journal was written (device/inode mismatch), skipping",
+            escape_path(path)
+        );
+        ctx.error(path, &e);
+        return None;
+    }
+    Some((fd, md.st_mode()))
+}
+
+/// Reverts a single journal record
+fn undo_record(ctx: &Ctx, record: &JournalRecord) {
+    let vp = &ctx.verbose_printer;
+    match record {
+        JournalRecord::Chown {
+            ptype,
+            dev,
+            ino,
+            current_id,
+            new_id,
+            path,
+        } => {
+            // the kernel clears setuid/setgid bits again when undoing, keep them with the handle
+            let (fd, mode) = match open_recorded(ctx, path, *dev, *ino) {
+                Some(r) => r,
+                None => return,
+            };
+            vp.print1(format!(
+                "{} -> Undo: Changing {} id from {} back to {}",
+                escape_path(path),
+                ptype,
+                new_id,
+                current_id,
+            ));
+            let event = Event {
+                ptype: Some(*ptype),
+                old_id: Some(*new_id),
+                new_id: Some(*current_id),
+                noop: ctx.noop,
+                ..Event::new(EventKind::Chown, path)
+            };
+            if ctx.noop {
+                vp.print1(format!("{} -> NOOP: Not making changes", escape_path(path)));
+                vp.event(event);
+                return;
+            }
+            // -1 leaves the other id alone
+            let (uid, gid) = match ptype {
+                PermissionType::User => (*current_id, u32::MAX),
+                PermissionType::Group => (u32::MAX, *current_id),
+            };
+            if let Err(e) = chown_handle(&fd, uid, gid) {
+                let e = anyhow::Error::new(e).context(format!(
+                    "{} -> Failed to restore {} id",
+                    escape_path(path),
+                    ptype
+                ));
+                ctx.error(path, &e);
+                return;
+            }
+            vp.event(event);
+            let is_symlink = mode & libc::S_IFMT == libc::S_IFLNK;
+            if is_symlink {
+                return;
+            }
+            if let Err(e) = files::restore_setid(&fd, mode) {
+                let e = e.context(format!(
+                    "{} -> Failed to restore setuid/setgid bits",
+                    escape_path(path)
+                ));
+                ctx.error(path, &e);
+            }
+        }
+        JournalRecord::Xattr {
+            name,
+            dev,
+            ino,
+            value,
+            path,
+        } => {
+            if !same_object(ctx, path, *dev, *ino) {
+                return;
+            }
+            vp.print1(format!(
+                "{} -> Undo: Restoring {} to {}",
+                escape_path(path),
+                name,
+                value,
+            ));
+            let event = Event {
+                xattr: Some(name),
+                noop: ctx.noop,
+                ..Event::new(EventKind::Xattr, path)
+            };
+            if ctx.noop {
+                vp.print1(format!("{} -> NOOP: Not making changes", escape_path(path)));
+                vp.event(event);
+                return;
+            }
+            let value = match capability::from_hex(value) {
+                Some(v) => v,
+                None => {
+                    let e = anyhow!("{} -> Invalid {} value", escape_path(path), name);
+                    ctx.error(path, &e);
+                    return;
+                }
+            };
+            match xattr::set(path, name, &value) {
+                Ok(_) => vp.event(event),
+                Err(e) => {
+                    let e = anyhow::Error::new(e).context(format!(
+                        "{} -> Failed to restore {}",
+                        escape_path(path),
+                        name
+                    ));
+                    ctx.error(path, &e);
+                }
+            }
+        }
+        JournalRecord::Acl {
+            atype,
+            dev,
+            ino,
+            entries,
+            path,
+        } => {
+            if !same_object(ctx, path, *dev, *ino) {
+                return;
+            }
+            vp.print1(format!(
+                "{} -> Undo: Restoring {} ACL to {}",
+                escape_path(path),
+                atype.as_str(),
+                entries,
+            ));
+            // the whole ACL is restored, so there is no single entry to report
+            let event = Event {
+                acl_type: Some(*atype),
+                noop: ctx.noop,
+                ..Event::new(EventKind::Acl, path)
+            };
+            if ctx.noop {
+                vp.print1(format!("{} -> NOOP: Not making changes", escape_path(path)));
+                vp.event(event);
+                return;
+            }
+            let prior = match acl::acl_from_string(entries) {
+                Ok(a) => a,
+                Err(e) => {
+                    ctx.error(
+                        path,
+                        &e.context(format!("{} -> Invalid ACL", escape_path(path))),
+                    );
+                    return;
+                }
+            };
+            // written as recorded, PosixACL::write_acl would recompute the mask
+            let value = acl::acl_to_xattr(&prior.entries());
+            match xattr::set(path, atype.xattr_name(), &value) {
+                Ok(_) => vp.event(event),
+                Err(e) => {
+                    let e = anyhow::Error::new(e)
+                        .context(format!("{} -> Failed to restore acl", escape_path(path)));
+                    ctx.error(path, &e);
+                }
+            }
+        }
+    }
+}
+
+/// Replays the journal at `path` in reverse, undoing every recorded change
+///
+/// # Arguments
+///
+/// * `ctx` - Context object used throughout the application
+///
+/// * `path` - Path to the journal file
+///
+pub fn undo(ctx: &Ctx, path: &Path) -> Result<(), anyhow::Error> {
+    let records = read_journal(path)?;
+    ctx.verbose_printer.print1(format!(
+        "{} -> Undoing {} journal entries",
+        escape_path(path),
+        records.len()
+    ));
+    for record in records.iter().rev() {
+        undo_record(ctx, record);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn journal_record_roundtrips_chown() {
+        let line = "chown\tgroup\t2049\t131\t57\t219883\t/data/with\\ttab\n";
+        let record = JournalRecord::from_line(line.trim_end_matches('\n')).unwrap();
+        match &record {
+            JournalRecord::Chown {
+                current_id,
+                new_id,
+                path,
+                ..
+            } => {
+                assert_eq!(*current_id, 57);
+                assert_eq!(*new_id, 219883);
+                assert_eq!(path, &PathBuf::from("/data/with\ttab"));
+            }
+            _ => panic!("expected a chown record"),
+        }
+        assert_eq!(record.to_line(), line);
+    }
+
+    #[test]
+    fn journal_record_roundtrips_acl() {
+        let line = "acl\tdefault\t2049\t131\tu::rwx,u:57:r--,g::r-x,m::r-x,o::---\t/data/shared\n";
+        let record = JournalRecord::from_line(line.trim_end_matches('\n')).unwrap();
+        assert!(matches!(record, JournalRecord::Acl { .. }));
+        assert_eq!(record.to_line(), line);
+    }
+
+    #[test]
+    fn journal_record_rejects_garbage() {
+        assert!(JournalRecord::from_line("chown\tuser\t1\t2\t3\t/data").is_err());
+        assert!(JournalRecord::from_line("chown\tother\t1\t2\t3\t4\t/data").is_err());
+        assert!(JournalRecord::from_line("mv\tuser\t1\t2\t3\t4\t/data").is_err());
+    }
+}
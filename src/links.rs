@@ -0,0 +1,38 @@
+use crate::util::lock;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+/// Shared set of (device, inode) pairs already processed, so an object with
+/// several hard links is only changed once
+#[derive(Debug, Default)]
+pub struct HardLinks {
+    visited: Mutex<HashSet<(u64, u64)>>,
+}
+
+impl HardLinks {
+    /// Marks the object as visited, returns true if this is the first time it was seen
+    ///
+    /// # Arguments
+    ///
+    /// * `dev` - Device the object lives on
+    ///
+    /// * `ino` - Inode of the object
+    ///
+    pub fn first_visit(&self, dev: u64, ino: u64) -> bool {
+        let mut visited = lock(&self.visited);
+        visited.insert((dev, ino))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_first_visit_is_reported() {
+        let links = HardLinks::default();
+        assert!(links.first_visit(1, 42));
+        assert!(!links.first_visit(1, 42));
+        assert!(links.first_visit(2, 42));
+    }
+}
@@ -1,34 +1,73 @@
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use events::OutputFormat;
+use nix::sys::signal::{SigSet, Signal};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use types::PermissionType;
 use util::VerbosePrinter;
 
+mod accounts;
 mod acl;
+mod capability;
+mod checkpoint;
 mod ctx;
+mod errors;
+mod events;
 mod files;
+mod fsobject;
+mod inventory;
+mod journal;
+mod links;
+mod nfs4;
 mod pairs;
+mod patterns;
+mod progress;
 mod run;
+mod stats;
+mod support;
 mod types;
 mod util;
 
 /// "Blazingly fast" filesystem modifier
 #[derive(Parser)]
-#[command(author, version, about, long_about = None, arg_required_else_help(true))]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    after_help = "/proc must be mounted, ACLs and extended attributes are read and written through /proc/self/fd.",
+    arg_required_else_help(true),
+    args_conflicts_with_subcommands(true)
+)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Base path(s) for enumeration
-    paths: Vec<String>,
+    paths: Vec<PathBuf>,
 
     /// Number of threads to spawn
     #[arg(short, long, default_value_t = 0)]
     threads: usize,
 
-    /// uid mapping (old:new)
+    /// uid mapping (old:new), ids or user names
     #[clap(short, long, value_parser, num_args = 0.., value_delimiter = ',')]
     uidpairs: Vec<String>,
 
-    /// gid mapping (old:new)
+    /// gid mapping (old:new), ids or group names
     #[clap(short, long, value_parser, num_args = 0.., value_delimiter = ',')]
     gidpairs: Vec<String>,
 
+    /// file of uid/gid mappings, one 'uid old new' or 'gid old new' per line
+    #[arg(long)]
+    map_file: Option<PathBuf>,
+
     /// don't modify unix permissions
     #[arg(long, default_value_t = false)]
     skip_permissions: bool,
@@ -37,43 +76,296 @@ struct Cli {
     #[arg(long, default_value_t = false)]
     skip_acls: bool,
 
-    /// ignore path patterns, comma separated
+    /// what to do when a rewritten ACL entry's new id already has an entry
+    #[arg(long, value_enum, default_value_t = acl::ConflictPolicy::Union)]
+    acl_conflict: acl::ConflictPolicy,
+
+    /// recompute the mask of rewritten ACLs like setfacl does, instead of keeping it
+    #[arg(long, default_value_t = false)]
+    recalculate_mask: bool,
+
+    /// don't restore setuid/setgid bits that the kernel clears when the owner changes
+    #[arg(long, default_value_t = false)]
+    drop_setid: bool,
+
+    /// don't descend into directories on other filesystems
+    #[arg(short = 'x', long, default_value_t = false)]
+    one_file_system: bool,
+
+    /// ignore paths matching gitignore-style patterns, comma separated
     #[clap(long, value_parser, num_args = 0.., value_delimiter = ',')]
     ignore_paths: Vec<String>,
 
-    /// dry run, don't change anything
+    /// append every change to this journal file before making it
     #[arg(long)]
+    journal: Option<PathBuf>,
+
+    /// show live progress on stderr, SIGUSR1 prints a snapshot at any time
+    #[arg(long, default_value_t = false)]
+    progress: bool,
+
+    /// save progress to this file periodically and on SIGINT/SIGTERM, not used with --noop
+    #[arg(long)]
+    checkpoint: Option<PathBuf>,
+
+    /// skip everything completed according to --checkpoint
+    #[arg(long, requires = "checkpoint", default_value_t = false)]
+    resume: bool,
+
+    /// seconds between checkpoint saves
+    #[arg(long, default_value_t = 60)]
+    checkpoint_interval: u64,
+
+    /// dry run, don't change anything
+    #[arg(long, global = true)]
     noop: bool,
 
     /// verbose, print operations
-    #[arg(short, long, action = clap::ArgAction::Count)]
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
     verbose: u8,
+
+    /// output format, jsonl prints one JSON object per event instead of messages
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Revert every change recorded in a journal, newest first
+    Undo {
+        /// Journal file written by a previous run with --journal
+        journal: PathBuf,
+    },
+    /// Report every uid and gid that owns files or appears in ACLs, with file counts and sizes
+    #[command(arg_required_else_help(true))]
+    Scan {
+        /// Base path(s) for enumeration
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+
+        /// Number of threads to spawn
+        #[arg(short, long, default_value_t = 0)]
+        threads: usize,
+
+        /// don't read unix acls
+        #[arg(long, default_value_t = false)]
+        skip_acls: bool,
+
+        /// don't descend into directories on other filesystems
+        #[arg(short = 'x', long, default_value_t = false)]
+        one_file_system: bool,
+
+        /// ignore paths matching gitignore-style patterns, comma separated
+        #[clap(long, value_parser, num_args = 0.., value_delimiter = ',')]
+        ignore_paths: Vec<String>,
+    },
+    /// Print a mapping file by matching names in old and new passwd/group files
+    #[command(arg_required_else_help(true))]
+    DiffAccounts {
+        /// passwd file from before the migration
+        #[arg(long, requires = "new_passwd")]
+        old_passwd: Option<PathBuf>,
+
+        /// passwd file from after the migration
+        #[arg(long, requires = "old_passwd")]
+        new_passwd: Option<PathBuf>,
+
+        /// group file from before the migration
+        #[arg(long, requires = "new_group")]
+        old_group: Option<PathBuf>,
+
+        /// group file from after the migration
+        #[arg(long, requires = "old_group")]
+        new_group: Option<PathBuf>,
+    },
+}
+
+/// Sets a flag when dropped, whether the scope it lives in ends normally or by a panic
+struct SetOnDrop<'a>(&'a AtomicBool);
+
+impl Drop for SetOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
 }
 
-fn main() -> Result<()> {
+fn main() -> ExitCode {
     let args = Cli::parse();
+    match run(args) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            ExitCode::from(errors::EXIT_ABORTED)
+        }
+    }
+}
+
+fn run(args: Cli) -> Result<ExitCode> {
+    fsobject::raise_open_files_limit();
+    match &args.command {
+        Some(Command::Undo { journal }) => {
+            let ctx = ctx::Ctx {
+                noop: args.noop,
+                skip_permissions: false,
+                skip_acls: false,
+                drop_setid: false,
+                acl_conflict: acl::ConflictPolicy::Union,
+                recalculate_mask: false,
+                uidmap: HashMap::new(),
+                gidmap: HashMap::new(),
+                one_file_system: false,
+                hard_links: links::HardLinks::default(),
+                acl_support: support::AclSupport::default(),
+                ignore_paths: patterns::IgnorePatterns::empty(),
+                journal: None,
+                checkpoint: None,
+                inventory: None,
+                stats: stats::Stats::default(),
+                errors: errors::ErrorRegistry::default(),
+                verbose_printer: VerbosePrinter::new(args.verbose, args.output),
+            };
+            journal::undo(&ctx, journal)?;
+            ctx.errors.print_summary(args.output);
+            return Ok(ctx.errors.exit_code());
+        }
+        Some(Command::Scan {
+            paths,
+            threads,
+            skip_acls,
+            one_file_system,
+            ignore_paths,
+        }) => {
+            if *threads > 0 {
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(*threads)
+                    .build_global()?;
+            }
+            let ctx = ctx::Ctx {
+                noop: true,
+                skip_permissions: false,
+                skip_acls: *skip_acls,
+                drop_setid: false,
+                acl_conflict: acl::ConflictPolicy::Union,
+                recalculate_mask: false,
+                uidmap: HashMap::new(),
+                gidmap: HashMap::new(),
+                one_file_system: *one_file_system,
+                hard_links: links::HardLinks::default(),
+                acl_support: support::AclSupport::default(),
+                ignore_paths: patterns::IgnorePatterns::new(ignore_paths)?,
+                journal: None,
+                checkpoint: None,
+                inventory: Some(inventory::Inventory::default()),
+                stats: stats::Stats::default(),
+                errors: errors::ErrorRegistry::default(),
+                verbose_printer: VerbosePrinter::new(args.verbose, args.output),
+            };
+            run::start(&ctx, paths);
+            if let Some(inventory) = &ctx.inventory {
+                inventory.print_summary(args.output);
+            }
+            ctx.acl_support.print_summary(args.output);
+            ctx.errors.print_summary(args.output);
+            return Ok(ctx.errors.exit_code());
+        }
+        Some(Command::DiffAccounts {
+            old_passwd,
+            new_passwd,
+            old_group,
+            new_group,
+        }) => {
+            if let (Some(old), Some(new)) = (old_passwd, new_passwd) {
+                accounts::print_mapping(old, new, PermissionType::User)?;
+            }
+            if let (Some(old), Some(new)) = (old_group, new_group) {
+                accounts::print_mapping(old, new, PermissionType::Group)?;
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+        None => (),
+    }
+
+    // pairs::check_pairs(&args.uidpairs, &args.gidpairs)?;
+
+    let mut uidmap = pairs::get_map_from_pairs(args.uidpairs, PermissionType::User)?;
+    let mut gidmap = pairs::get_map_from_pairs(args.gidpairs, PermissionType::Group)?;
+    if let Some(map_file) = &args.map_file {
+        pairs::read_map_file(map_file, &mut uidmap, &mut gidmap)?;
+    }
+
+    // a dry run must not record progress that a real run would then skip
+    let checkpoint = match &args.checkpoint {
+        Some(path) if !args.noop => {
+            let fingerprint = checkpoint::fingerprint(&uidmap, &gidmap);
+            let cp = match args.resume {
+                true => checkpoint::Checkpoint::resume(path, fingerprint)?,
+                false => checkpoint::Checkpoint::new(path, fingerprint),
+            };
+            Some(Arc::new(cp))
+        }
+        _ => None,
+    };
+    // signals are received by waiter threads, so block them before any thread is
+    // started, every thread inherits the mask and none of them is killed by a signal
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGUSR1);
+    if checkpoint.is_some() {
+        signals.add(Signal::SIGINT);
+        signals.add(Signal::SIGTERM);
+    }
+    signals.thread_block().context("Failed to block signals")?;
+    if let Some(cp) = &checkpoint {
+        checkpoint::spawn_signal_handler(cp.clone());
+        checkpoint::spawn_saver(cp.clone(), Duration::from_secs(args.checkpoint_interval));
+    }
+
+    let progress = progress::Progress::new(args.progress, progress::estimate_entries(&args.paths));
+
     if args.threads > 0 {
         rayon::ThreadPoolBuilder::new()
             .num_threads(args.threads)
             .build_global()?;
     }
 
-    // pairs::check_pairs(&args.uidpairs, &args.gidpairs)?;
-
-    let uidmap = pairs::get_map_from_pairs(args.uidpairs)?;
-    let gidmap = pairs::get_map_from_pairs(args.gidpairs)?;
-
+    let stats = stats::Stats::new(&uidmap, &gidmap);
     let ctx = ctx::Ctx {
         noop: args.noop,
         skip_permissions: args.skip_permissions,
         skip_acls: args.skip_acls,
+        drop_setid: args.drop_setid,
+        acl_conflict: args.acl_conflict,
+        recalculate_mask: args.recalculate_mask,
         uidmap,
         gidmap,
-        ignore_paths: args.ignore_paths,
-        verbose_printer: VerbosePrinter::new(args.verbose),
+        one_file_system: args.one_file_system,
+        hard_links: links::HardLinks::default(),
+        acl_support: support::AclSupport::default(),
+        ignore_paths: patterns::IgnorePatterns::new(&args.ignore_paths)?,
+        journal: match &args.journal {
+            Some(path) if !args.noop => Some(journal::Journal::open(path)?),
+            _ => None,
+        },
+        checkpoint,
+        inventory: None,
+        stats,
+        errors: errors::ErrorRegistry::default(),
+        verbose_printer: VerbosePrinter::new(args.verbose, args.output),
     };
 
-    run::start(&ctx, &args.paths);
+    let done = AtomicBool::new(false);
+    thread::scope(|s| {
+        s.spawn(|| progress.report(&ctx, &done));
+        // set on drop, so a panic in the run still stops the progress thread
+        // instead of leaving the scope waiting for it forever
+        let _done = SetOnDrop(&done);
+        run::start(&ctx, &args.paths);
+    });
+    if let Some(cp) = &ctx.checkpoint {
+        cp.save()?;
+    }
+    ctx.stats.print_summary(ctx.noop, args.output);
+    ctx.acl_support.print_summary(args.output);
+    ctx.errors.print_summary(args.output);
 
-    Ok(())
+    Ok(ctx.errors.exit_code())
 }
@@ -0,0 +1,321 @@
+use crate::acl::AclXattrs;
+use crate::ctx::Ctx;
+use crate::events::{Event, EventKind};
+use crate::fsobject::{get_xattr, set_xattr, FsObject};
+use crate::types::{AclType, PermissionType};
+use crate::util::escape_path;
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::os::fd::OwnedFd;
+
+/// NFSv4 ACL of the Linux NFS client, principals are `user@domain` or numeric text
+pub const NFS4_ACL_XATTR: &str = "system.nfs4_acl";
+/// NFSv4 ACL of ZFS, principals are numeric ids
+pub const NFS4_ACL_XDR_XATTR: &str = "system.nfs4_acl_xdr";
+
+/// ACE flag marking the principal as a group rather than a user
+const ACE4_IDENTIFIER_GROUP: u32 = 0x40;
+/// `system.nfs4_acl_xdr` flag marking OWNER@, GROUP@ or EVERYONE@ rather than an id
+const ACEI4_SPECIAL_WHO: u32 = 0x1;
+
+/// The principal of an ACE whose id is in the mappings
+#[derive(Debug, PartialEq)]
+struct Nfs4Remap {
+    ptype: PermissionType,
+    old_id: u32,
+    new_id: u32,
+}
+
+/// Reads the big endian values of an XDR encoded buffer
+struct XdrReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> XdrReader<'a> {
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = match self.buf.get(self.pos..self.pos + 4) {
+            Some(b) => b,
+            None => bail!("NFSv4 ACL is truncated"),
+        };
+        self.pos += 4;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Reads variable length opaque data, padded to a multiple of 4 bytes
+    fn opaque(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        let padded = len.next_multiple_of(4);
+        if self.buf.len() - self.pos < padded {
+            bail!("NFSv4 ACL is truncated");
+        }
+        let data = &self.buf[self.pos..self.pos + len];
+        self.pos += padded;
+        Ok(data)
+    }
+
+    fn finish(&self) -> Result<()> {
+        if self.pos != self.buf.len() {
+            bail!("NFSv4 ACL has {} trailing bytes", self.buf.len() - self.pos);
+        }
+        Ok(())
+    }
+}
+
+fn put_opaque(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(data);
+    out.resize(out.len().next_multiple_of(4), 0);
+}
+
+/// Returns the remap of a numeric principal, None if its id isn't mapped
+fn remap_id(
+    flag: u32,
+    id: u32,
+    uidmap: &HashMap<u32, u32>,
+    gidmap: &HashMap<u32, u32>,
+) -> Option<Nfs4Remap> {
+    let (ptype, new_id) = match flag & ACE4_IDENTIFIER_GROUP {
+        0 => (PermissionType::User, uidmap.get(&id)?),
+        _ => (PermissionType::Group, gidmap.get(&id)?),
+    };
+    Some(Nfs4Remap {
+        ptype,
+        old_id: id,
+        new_id: *new_id,
+    })
+}
+
+/// Rewrites the numeric principals of a `system.nfs4_acl` value, a count followed by
+/// type, flag, access mask and a text principal for every ACE.
+/// Principals that are names, like `alice@example.com` or `OWNER@`, are left alone.
+///
+/// # Arguments
+///
+/// * `value` - Raw value of the xattr
+///
+/// * `uidmap` - Map of old:new uids
+///
+/// * `gidmap` - Map of old:new gids
+///
+fn remap_nfs4_acl(
+    value: &[u8],
+    uidmap: &HashMap<u32, u32>,
+    gidmap: &HashMap<u32, u32>,
+) -> Result<(Vec<u8>, Vec<Nfs4Remap>)> {
+    let mut xdr = XdrReader { buf: value, pos: 0 };
+    let mut out = vec![];
+    let mut remaps = vec![];
+    let naces = xdr.u32()?;
+    out.extend_from_slice(&naces.to_be_bytes());
+    for _ in 0..naces {
+        let (acetype, flag, mask) = (xdr.u32()?, xdr.u32()?, xdr.u32()?);
+        let mut who = xdr.opaque()?.to_vec();
+        let id = match std::str::from_utf8(&who) {
+            Ok(text) if text.bytes().all(|b| b.is_ascii_digit()) => text.parse::<u32>().ok(),
+            _ => None,
+        };
+        if let Some(r) = id.and_then(|id| remap_id(flag, id, uidmap, gidmap)) {
+            who = r.new_id.to_string().into_bytes();
+            remaps.push(r);
+        }
+        for v in [acetype, flag, mask] {
+            out.extend_from_slice(&v.to_be_bytes());
+        }
+        put_opaque(&mut out, &who);
+    }
+    xdr.finish()?;
+    Ok((out, remaps))
+}
+
+/// Rewrites the principals of a `system.nfs4_acl_xdr` value, the ACL flags and a count
+/// followed by type, flag, iflag, access mask and a numeric principal for every ACE
+///
+/// # Arguments
+///
+/// * `value` - Raw value of the xattr
+///
+/// * `uidmap` - Map of old:new uids
+///
+/// * `gidmap` - Map of old:new gids
+///
+fn remap_nfs4_acl_xdr(
+    value: &[u8],
+    uidmap: &HashMap<u32, u32>,
+    gidmap: &HashMap<u32, u32>,
+) -> Result<(Vec<u8>, Vec<Nfs4Remap>)> {
+    let mut xdr = XdrReader { buf: value, pos: 0 };
+    let mut out = value.to_vec();
+    let mut remaps = vec![];
+    let _acl_flag = xdr.u32()?;
+    let naces = xdr.u32()?;
+    for _ in 0..naces {
+        let (_acetype, flag, iflag, _mask) = (xdr.u32()?, xdr.u32()?, xdr.u32()?, xdr.u32()?);
+        let who_pos = xdr.pos;
+        let who = xdr.u32()?;
+        if iflag & ACEI4_SPECIAL_WHO != 0 {
+            continue;
+        }
+        if let Some(r) = remap_id(flag, who, uidmap, gidmap) {
+            out[who_pos..who_pos + 4].copy_from_slice(&r.new_id.to_be_bytes());
+            remaps.push(r);
+        }
+    }
+    xdr.finish()?;
+    Ok((out, remaps))
+}
+
+/// Using the data in our context, update the NFSv4 ACLs on the given object
+///
+/// # Arguments
+///
+/// * `ctx` - Context object used throughout the application
+///
+/// * `obj` - The filesystem object
+///
+/// * `fd` - Handle on the object, see `FsObject::open_path`
+///
+/// * `xattrs` - Extended attributes of the object, only listed ACLs are read
+///
+pub fn update_nfs4_acl(ctx: &Ctx, obj: &FsObject, fd: &OwnedFd, xattrs: &AclXattrs) {
+    let vp = &ctx.verbose_printer;
+    let path = obj.path;
+    for name in [NFS4_ACL_XATTR, NFS4_ACL_XDR_XATTR] {
+        if !xattrs.has(name) {
+            continue;
+        }
+        let value = match get_xattr(fd, name) {
+            Ok(Some(v)) => v,
+            Ok(None) => continue,
+            Err(e) => {
+                let e = anyhow::Error::new(e)
+                    .context(format!("{} -> Error reading {name}", escape_path(path)));
+                ctx.error(path, &e);
+                continue;
+            }
+        };
+        let remapped = match name {
+            NFS4_ACL_XATTR => remap_nfs4_acl(&value, &ctx.uidmap, &ctx.gidmap),
+            _ => remap_nfs4_acl_xdr(&value, &ctx.uidmap, &ctx.gidmap),
+        };
+        let (remapped, remaps) = match remapped {
+            Ok(r) => r,
+            Err(e) => {
+                ctx.error(path, &e.context(format!("{} -> {name}", escape_path(path))));
+                continue;
+            }
+        };
+        if remaps.is_empty() {
+            continue;
+        }
+        for r in &remaps {
+            vp.print1(format!(
+                "{} -> {} id {} found in NFSv4 ACL, replacing with id {}",
+                escape_path(path),
+                r.ptype,
+                r.old_id,
+                r.new_id,
+            ));
+        }
+        if ctx.noop {
+            vp.print1(format!("{} -> NOOP: Not making changes", escape_path(path)));
+        } else {
+            if let Some(journal) = &ctx.journal {
+                let (dev, ino) = (obj.stat.st_dev, obj.stat.st_ino);
+                if let Err(e) = journal.record_xattr(path, name, dev, ino, &value) {
+                    ctx.error(path, &e);
+                    continue;
+                }
+            }
+            vp.print1(format!(
+                "{} -> Writing changes to NFSv4 ACL",
+                escape_path(path)
+            ));
+            if let Err(e) = set_xattr(fd, name, &remapped) {
+                let e = anyhow::Error::new(e)
+                    .context(format!("{} -> Failed to write {name}", escape_path(path)));
+                ctx.error(path, &e);
+                continue;
+            }
+        }
+        for r in &remaps {
+            // NFSv4 has no separate default ACL, inheritance is a flag on each ACE
+            ctx.stats.add_acl_entry(r.ptype, r.old_id, AclType::Access);
+            vp.event(Event {
+                ptype: Some(r.ptype),
+                old_id: Some(r.old_id),
+                new_id: Some(r.new_id),
+                acl_type: Some(AclType::Access),
+                noop: ctx.noop,
+                ..Event::new(EventKind::Acl, path)
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ace(out: &mut Vec<u8>, flag: u32, who: &str) {
+        for v in [0, flag, 0x0012_0081] {
+            out.extend_from_slice(&v.to_be_bytes());
+        }
+        put_opaque(out, who.as_bytes());
+    }
+
+    #[test]
+    fn remaps_numeric_principals() {
+        let mut value = 3u32.to_be_bytes().to_vec();
+        ace(&mut value, 0, "OWNER@");
+        ace(&mut value, 0, "57");
+        ace(&mut value, ACE4_IDENTIFIER_GROUP, "57");
+        let uidmap = HashMap::from([(57, 219883)]);
+        let gidmap = HashMap::from([(57, 7)]);
+        let (out, remaps) = remap_nfs4_acl(&value, &uidmap, &gidmap).unwrap();
+
+        let mut expected = 3u32.to_be_bytes().to_vec();
+        ace(&mut expected, 0, "OWNER@");
+        ace(&mut expected, 0, "219883");
+        ace(&mut expected, ACE4_IDENTIFIER_GROUP, "7");
+        assert_eq!(out, expected);
+        assert_eq!(remaps.len(), 2);
+        assert_eq!(remaps[1].ptype, PermissionType::Group);
+
+        assert!(remap_nfs4_acl(&value[..value.len() - 1], &uidmap, &gidmap).is_err());
+    }
+
+    #[test]
+    fn remaps_xdr_ids_but_not_special_principals() {
+        let mut value = vec![];
+        // acl flags, 2 aces: OWNER@ and uid 57
+        for v in [
+            0,
+            2,
+            0,
+            0,
+            ACEI4_SPECIAL_WHO,
+            0x0012_0081,
+            1,
+            0,
+            0,
+            0,
+            0x0012_0081,
+            57,
+        ] {
+            value.extend_from_slice(&u32::to_be_bytes(v));
+        }
+        let uidmap = HashMap::from([(57, 219883), (1, 2)]);
+        let (out, remaps) = remap_nfs4_acl_xdr(&value, &uidmap, &HashMap::new()).unwrap();
+        assert_eq!(out[..value.len() - 4], value[..value.len() - 4]);
+        assert_eq!(out[value.len() - 4..], 219883u32.to_be_bytes());
+        assert_eq!(
+            remaps,
+            vec![Nfs4Remap {
+                ptype: PermissionType::User,
+                old_id: 57,
+                new_id: 219883,
+            }]
+        );
+    }
+}
@@ -1,5 +1,10 @@
+use crate::types::PermissionType;
+use crate::util::escape_path;
 use anyhow::{bail, Result};
+use nix::unistd::{Group, User};
 use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
 
 /// Contains a relationship of a current uid/gid to a new uid/gid
 #[derive(Debug)]
@@ -10,34 +15,81 @@ pub struct Idpair {
     new_id: u32,
 }
 
+/// Returns the numeric id for `id`, which is either already numeric
+/// or a user/group name that is resolved through NSS
+///
+/// # Arguments
+///
+/// * `id` - Numeric id or name
+///
+/// * `ptype` - Whether `id` is a user or a group
+///
+fn resolve_id(id: &str, ptype: PermissionType) -> Result<u32, anyhow::Error> {
+    if let Ok(o) = id.parse::<u32>() {
+        return Ok(o);
+    }
+    let resolved = match ptype {
+        PermissionType::User => User::from_name(id).map(|u| u.map(|u| u.uid.as_raw())),
+        PermissionType::Group => Group::from_name(id).map(|g| g.map(|g| g.gid.as_raw())),
+    };
+    match resolved {
+        Ok(Some(o)) => Ok(o),
+        Ok(None) => bail!("Unable to resolve {} name '{id}' to an id", ptype.as_str()),
+        Err(e) => bail!(
+            "Unable to resolve {} name '{id}' to an id: {e}",
+            ptype.as_str()
+        ),
+    }
+}
+
 impl Idpair {
-    /// Parse an Idpair from a string
+    /// Parse an Idpair from a string. Either side can be a numeric id
+    /// or a name, which is resolved to an id through NSS.
     ///
     /// # Example
     ///
     /// ```
-    /// let pair = Idpair::from_string("57:211790")
+    /// let pair = Idpair::from_string("57:211790", PermissionType::User)
     /// // Pair {
     /// //     current_id: 57,
     /// //     new_id: 211790,
     /// // }
+    /// let pair = Idpair::from_string("alice:alice_new", PermissionType::User)
     /// ```
     ///
-    pub fn from_string(idpair: &str) -> Result<Option<Idpair>, anyhow::Error> {
-        let current_id = match idpair.split(':').nth(0) {
-            Some(s) => match s.parse::<u32>() {
-                Ok(o) => o,
-                Err(_) => bail!("Unable to parse id string '{s}' to int"),
-            },
+    pub fn from_string(
+        idpair: &str,
+        ptype: PermissionType,
+    ) -> Result<Option<Idpair>, anyhow::Error> {
+        let current = match idpair.split(':').nth(0) {
+            Some(s) => s,
             None => bail!("Invalid idpair. Expected format (old:new) '890:211790'"),
         };
-        let new_id = match idpair.split(':').nth(1) {
-            Some(s) => match s.parse::<u32>() {
-                Ok(o) => o,
-                Err(_) => bail!("Unable to parse id string '{s}' to int"),
-            },
+        let new = match idpair.split(':').nth(1) {
+            Some(s) => s,
             None => bail!("Invalid idpair. expected format (old:new) '890:211790'"),
         };
+        Idpair::from_fields(current, new, ptype)
+    }
+
+    /// Parse an Idpair from separate current and new id strings.
+    /// Returns `None` if both ids are identical.
+    ///
+    /// # Arguments
+    ///
+    /// * `current` - The id that will be overwritten
+    ///
+    /// * `new` - The new id for the object
+    ///
+    /// * `ptype` - Whether the ids are for users or groups
+    ///
+    pub fn from_fields(
+        current: &str,
+        new: &str,
+        ptype: PermissionType,
+    ) -> Result<Option<Idpair>, anyhow::Error> {
+        let current_id = resolve_id(current, ptype)?;
+        let new_id = resolve_id(new, ptype)?;
         if current_id == new_id {
             return Ok(None);
         }
@@ -53,35 +105,136 @@ impl Idpair {
 ///
 /// * `pairs` - List of strings to be converted to pairs, then stored in the `HashMap`
 ///
-pub fn get_map_from_pairs(pairs: Vec<String>) -> Result<HashMap<u32, u32>, anyhow::Error> {
+/// * `ptype` - Whether the pairs are for users or groups
+///
+pub fn get_map_from_pairs(
+    pairs: Vec<String>,
+    ptype: PermissionType,
+) -> Result<HashMap<u32, u32>, anyhow::Error> {
     let mut idmap: HashMap<u32, u32> = HashMap::new();
     for pair in pairs {
-        let maybe_u = Idpair::from_string(&pair)?;
+        let maybe_u = Idpair::from_string(&pair, ptype)?;
         match maybe_u {
             None => {
-                println!("Skipping idpair with identical old and new id: {}", pair);
+                eprintln!("Skipping idpair with identical old and new id: {}", pair);
                 continue;
             }
-            Some(u) => {
-                if let Some(previous_new_id) = idmap.insert(u.current_id, u.new_id) {
-                    // insert returns the value at that key if it already exists.
-                    // lets compare that to our new_id and bail if it's different
-                    if previous_new_id != u.new_id {
-                        bail!(
-                            "Conflight with ID '{}', trying to change it to both '{}' and '{}'",
-                            u.current_id,
-                            previous_new_id,
-                            u.new_id
-                        )
-                    }
-                    // returns None if it doesnt exist, inserted successfully.
-                }
-            }
+            Some(u) => insert_idpair(&mut idmap, u)?,
         };
     }
     Ok(idmap)
 }
 
+/// Inserts an `Idpair` into the map, returning an error if the current id
+/// is already being changed to a different new id
+///
+/// # Arguments
+///
+/// * `idmap` - Map of current_id:new_id to insert into
+///
+/// * `u` - The `Idpair` to insert
+///
+fn insert_idpair(idmap: &mut HashMap<u32, u32>, u: Idpair) -> Result<(), anyhow::Error> {
+    if let Some(previous_new_id) = idmap.insert(u.current_id, u.new_id) {
+        // insert returns the value at that key if it already exists.
+        // lets compare that to our new_id and bail if it's different
+        if previous_new_id != u.new_id {
+            bail!(
+                "Conflight with ID '{}', trying to change it to both '{}' and '{}'",
+                u.current_id,
+                previous_new_id,
+                u.new_id
+            )
+        }
+        // returns None if it doesnt exist, inserted successfully.
+    }
+    Ok(())
+}
+
+/// Reads a mapping file and adds every entry to the uid and gid maps.
+///
+/// Each line is `uid <old> <new>` or `gid <old> <new>`, separated by
+/// whitespace, tabs or commas. Ids can be numeric or names.
+/// Blank lines and anything after `#` are ignored.
+///
+/// # Example
+///
+/// ```text
+/// # old accounts -> new accounts
+/// uid 57 219883
+/// gid,890,211790
+/// ```
+///
+/// # Arguments
+///
+/// * `path` - Path to the mapping file
+///
+/// * `uidmap` - Map that `uid` lines are added to
+///
+/// * `gidmap` - Map that `gid` lines are added to
+///
+pub fn read_map_file(
+    path: &Path,
+    uidmap: &mut HashMap<u32, u32>,
+    gidmap: &mut HashMap<u32, u32>,
+) -> Result<(), anyhow::Error> {
+    let contents = match fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) => bail!("{} -> Failed to read map file: {e}", escape_path(path)),
+    };
+    for (i, line) in contents.lines().enumerate() {
+        if let Err(e) = parse_map_line(line, uidmap, gidmap) {
+            bail!("{}:{} -> {e}", escape_path(path), i + 1);
+        }
+    }
+    Ok(())
+}
+
+/// Parses a single line of a mapping file into the uid or gid map
+///
+/// # Arguments
+///
+/// * `line` - Line from the mapping file
+///
+/// * `uidmap` - Map that `uid` lines are added to
+///
+/// * `gidmap` - Map that `gid` lines are added to
+///
+fn parse_map_line(
+    line: &str,
+    uidmap: &mut HashMap<u32, u32>,
+    gidmap: &mut HashMap<u32, u32>,
+) -> Result<(), anyhow::Error> {
+    // strip comments
+    let line = match line.split_once('#') {
+        Some((before, _)) => before,
+        None => line,
+    };
+    let fields: Vec<&str> = line
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|f| !f.is_empty())
+        .collect();
+    if fields.is_empty() {
+        return Ok(());
+    }
+    if fields.len() != 3 {
+        bail!("Invalid mapping. Expected format (type old new) 'uid 890 211790'");
+    }
+    let (idmap, ptype) = match fields[0] {
+        "uid" => (uidmap, PermissionType::User),
+        "gid" => (gidmap, PermissionType::Group),
+        o => bail!("Invalid mapping type '{o}', expected 'uid' or 'gid'"),
+    };
+    match Idpair::from_fields(fields[1], fields[2], ptype)? {
+        None => eprintln!(
+            "Skipping idpair with identical old and new id: {}:{}",
+            fields[1], fields[2]
+        ),
+        Some(u) => insert_idpair(idmap, u)?,
+    }
+    Ok(())
+}
+
 /// Returns an error if pairs don't pass checks
 ///
 /// # Arguments
@@ -153,6 +306,46 @@ fn flatten_pairs(uidpairs: &Vec<String>, gidpairs: &Vec<String>) -> Vec<String>
 mod tests {
     use super::*;
 
+    #[test]
+    fn parse_map_line_handles_separators_and_comments() {
+        let mut uidmap = HashMap::new();
+        let mut gidmap = HashMap::new();
+        for line in [
+            "# header comment",
+            "",
+            "uid 57 219883",
+            "gid\t890\t211790  # trailing comment",
+            "uid,58,219884",
+            "uid 59 59",
+        ] {
+            parse_map_line(line, &mut uidmap, &mut gidmap).unwrap();
+        }
+        assert_eq!(uidmap, HashMap::from([(57, 219883), (58, 219884)]));
+        assert_eq!(gidmap, HashMap::from([(890, 211790)]));
+    }
+
+    #[test]
+    fn parse_map_line_rejects_bad_lines() {
+        let mut uidmap = HashMap::new();
+        let mut gidmap = HashMap::new();
+        assert!(parse_map_line("uid 57", &mut uidmap, &mut gidmap).is_err());
+        assert!(parse_map_line("pid 57 58", &mut uidmap, &mut gidmap).is_err());
+        assert!(parse_map_line("uid 57 abc", &mut uidmap, &mut gidmap).is_err());
+        parse_map_line("uid 57 58", &mut uidmap, &mut gidmap).unwrap();
+        assert!(parse_map_line("uid 57 59", &mut uidmap, &mut gidmap).is_err());
+    }
+
+    #[test]
+    fn idpair_resolves_names() {
+        let pair = Idpair::from_string("root:211790", PermissionType::User)
+            .unwrap()
+            .unwrap();
+        assert_eq!(pair.current_id, 0);
+        assert_eq!(pair.new_id, 211790);
+        assert!(Idpair::from_string("no-such-user-xyz:5", PermissionType::User).is_err());
+        assert!(Idpair::from_string("5:no-such-group-xyz", PermissionType::Group).is_err());
+    }
+
     #[test]
     fn check_pair_duplicates_flattens_correctly() {
         let control = vec![
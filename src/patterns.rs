@@ -0,0 +1,113 @@
+use crate::util::absolute_path;
+use anyhow::{Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::path::Path;
+
+/// Compiled set of gitignore-style patterns for `--ignore-paths`.
+///
+/// Patterns containing a `/` are anchored at the filesystem root, such as `/data/*/scratch`,
+/// patterns without one match a whole path component at any depth, such as `.snapshot`.
+/// `**` matches any number of directories and a leading `!` re-includes a path.
+#[derive(Debug)]
+pub struct IgnorePatterns {
+    matcher: Gitignore,
+}
+
+impl IgnorePatterns {
+    /// Compiles the patterns once, in order, later patterns taking precedence
+    ///
+    /// # Arguments
+    ///
+    /// * `patterns` - List of gitignore-style patterns
+    ///
+    pub fn new(patterns: &[String]) -> Result<Self> {
+        let mut builder = GitignoreBuilder::new("/");
+        for p in patterns {
+            builder
+                .add_line(None, p)
+                .with_context(|| format!("Invalid ignore pattern '{p}'"))?;
+        }
+        let matcher = builder
+            .build()
+            .context("Failed to compile ignore patterns")?;
+        Ok(IgnorePatterns { matcher })
+    }
+
+    /// Returns an empty set of patterns that never matches
+    pub fn empty() -> Self {
+        IgnorePatterns {
+            matcher: Gitignore::empty(),
+        }
+    }
+
+    /// Returns the pattern that excludes `path`, or None if it should be processed
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the filesystem object, relative paths are resolved against the current directory
+    ///
+    /// * `is_dir` - Whether the path is a directory, for patterns ending in `/`
+    ///
+    pub fn matched(&self, path: &Path, is_dir: bool) -> Option<&str> {
+        if self.matcher.is_empty() {
+            return None;
+        }
+        match self.matcher.matched(absolute_path(path), is_dir) {
+            Match::Ignore(glob) => Some(glob.original()),
+            Match::Whitelist(_) | Match::None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(p: &[&str]) -> IgnorePatterns {
+        let p: Vec<String> = p.iter().map(|s| s.to_string()).collect();
+        IgnorePatterns::new(&p).unwrap()
+    }
+
+    #[test]
+    fn unanchored_matches_whole_component() {
+        let ip = patterns(&[".snapshot"]);
+        assert_eq!(
+            ip.matched(Path::new("/data/a/.snapshot"), true),
+            Some(".snapshot")
+        );
+        assert_eq!(ip.matched(Path::new("/data/foo.snapshot"), true), None);
+    }
+
+    #[test]
+    fn anchored_and_double_star() {
+        let ip = patterns(&["/data/*/scratch", "**/tmp/*.log"]);
+        assert_eq!(
+            ip.matched(Path::new("/data/alice/scratch"), true),
+            Some("/data/*/scratch")
+        );
+        assert_eq!(ip.matched(Path::new("/other/alice/scratch"), true), None);
+        assert_eq!(ip.matched(Path::new("/data/alice/bob/scratch"), true), None);
+        assert_eq!(
+            ip.matched(Path::new("/data/a/b/tmp/x.log"), false),
+            Some("**/tmp/*.log")
+        );
+    }
+
+    #[test]
+    fn negation_reincludes() {
+        let ip = patterns(&["*.tmp", "!keep.tmp"]);
+        assert_eq!(ip.matched(Path::new("/data/x.tmp"), false), Some("*.tmp"));
+        assert_eq!(ip.matched(Path::new("/data/keep.tmp"), false), None);
+    }
+
+    #[test]
+    fn matches_non_utf8_paths() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let ip = patterns(&["/data/*/scratch"]);
+        let path = Path::new(OsStr::from_bytes(b"/data/caf\xe9/scratch"));
+        assert_eq!(ip.matched(path, true), Some("/data/*/scratch"));
+    }
+}
@@ -0,0 +1,155 @@
+use crate::ctx::Ctx;
+use nix::sys::signal::{SigSet, Signal};
+use nix::sys::statfs::statfs;
+use std::collections::HashSet;
+use std::io::{self, IsTerminal, Write};
+use std::os::linux::fs::MetadataExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the live display is redrawn
+const REDRAW_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the reporter checks for a finished run or a snapshot request
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Reports progress of a run on stderr, either as a live line redrawn
+/// every second or as a snapshot line whenever SIGUSR1 arrives
+pub struct Progress {
+    /// When the run started, for the rate
+    started: Instant,
+    /// Estimated number of entries in the run, from the used inodes of the filesystems
+    estimate: Option<u64>,
+    /// Redraw a live line, only when stderr is a terminal
+    live: bool,
+    /// Set by the signal handler when a snapshot was requested
+    snapshot_requested: Arc<AtomicBool>,
+}
+
+/// Returns the number of used inodes on the filesystems of the base paths,
+/// counting each filesystem once. This is only an upper bound when a base path
+/// is a subdirectory, but it gives an ETA that improves as the run goes on.
+///
+/// # Arguments
+///
+/// * `paths` - Base paths of the run
+///
+pub fn estimate_entries<P: AsRef<Path>>(paths: &[P]) -> Option<u64> {
+    let mut devices = HashSet::new();
+    let mut total = 0;
+    for p in paths {
+        let p = p.as_ref();
+        let dev = p.metadata().ok()?.st_dev();
+        if !devices.insert(dev) {
+            continue;
+        }
+        let fs = statfs(p).ok()?;
+        total += fs.files().saturating_sub(fs.files_free());
+    }
+    Some(total)
+}
+
+/// Formats a duration as `h:mm:ss`
+fn format_duration(secs: u64) -> String {
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+impl Progress {
+    /// Sets up progress reporting, SIGUSR1 always prints a snapshot.
+    /// SIGUSR1 must already be blocked in every thread, see `main`,
+    /// so only the waiter thread started here receives it.
+    ///
+    /// # Arguments
+    ///
+    /// * `live` - Redraw a progress line every second if stderr is a terminal
+    ///
+    /// * `estimate` - Estimated number of entries, see `estimate_entries`
+    ///
+    pub fn new(live: bool, estimate: Option<u64>) -> Progress {
+        let snapshot_requested = Arc::new(AtomicBool::new(false));
+        let mut signals = SigSet::empty();
+        signals.add(Signal::SIGUSR1);
+        let requested = snapshot_requested.clone();
+        thread::spawn(move || loop {
+            match signals.wait() {
+                Ok(_) => requested.store(true, Ordering::Relaxed),
+                Err(e) => {
+                    eprintln!("Failed to wait for signals: {e}");
+                    return;
+                }
+            }
+        });
+        Progress {
+            started: Instant::now(),
+            estimate,
+            live: live && io::stderr().is_terminal(),
+            snapshot_requested,
+        }
+    }
+
+    /// Returns a single line describing the progress so far
+    fn line(&self, ctx: &Ctx) -> String {
+        let scanned = ctx.stats.scanned();
+        let changed = ctx.stats.changed();
+        let acl_entries = ctx.stats.acl_entries();
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = match elapsed > 0.0 {
+            true => scanned as f64 / elapsed,
+            false => 0.0,
+        };
+        let eta = match self.estimate {
+            Some(total) if total > scanned && rate > 0.0 => {
+                format_duration(((total - scanned) as f64 / rate) as u64)
+            }
+            _ => "-".to_string(),
+        };
+        format!(
+            "scanned {scanned}, changed {changed}, ACL entries {acl_entries}, errors {}, {rate:.0}/s, elapsed {}, ETA {eta}",
+            ctx.errors.total(),
+            format_duration(elapsed as u64),
+        )
+    }
+
+    /// Reports progress until `done` is set, meant to run on its own thread next to `run::start`
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - Context object used throughout the application
+    ///
+    /// * `done` - Set once the run has finished
+    ///
+    pub fn report(&self, ctx: &Ctx, done: &AtomicBool) {
+        let mut last_draw = Instant::now();
+        while !done.load(Ordering::Relaxed) {
+            thread::sleep(POLL_INTERVAL);
+            if self.snapshot_requested.swap(false, Ordering::Relaxed) {
+                match self.live {
+                    true => eprintln!("\r\x1b[K{}", self.line(ctx)),
+                    false => eprintln!("{}", self.line(ctx)),
+                }
+            }
+            if self.live && last_draw.elapsed() >= REDRAW_INTERVAL {
+                eprint!("\r\x1b[K{}", self.line(ctx));
+                let _ = io::stderr().flush();
+                last_draw = Instant::now();
+            }
+        }
+        if self.live {
+            eprintln!("\r\x1b[K{}", self.line(ctx));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_duration() {
+        assert_eq!(format_duration(0), "0:00:00");
+        assert_eq!(format_duration(3725), "1:02:05");
+        assert_eq!(format_duration(90000), "25:00:00");
+    }
+}
@@ -1,55 +1,180 @@
 use crate::ctx::Ctx;
+use crate::errors;
+use crate::events::{Event, EventKind};
 use crate::files;
+use crate::fsobject::FsObject;
+use crate::util::escape_path;
 use rayon::prelude::*;
+use std::os::fd::AsRawFd;
 use std::path::Path;
 
 /// Main recursive function that operates on directories.
+/// Returns true if the object and everything below it was processed without errors.
 ///
 /// # Arguments
 ///
 /// * `ctx` - Context object used throughout the application
 ///
-/// * `path` - Path to the filesystem object
+/// * `obj` - The filesystem object, relative to its open parent directory
+///
+/// * `base_dev` - Device of the base path, set if recursion should stay on that filesystem
 ///
-pub fn run_recurse(ctx: &Ctx, path: &Path) {
+pub fn run_recurse(ctx: &Ctx, obj: &FsObject, base_dev: Option<u64>) -> bool {
     // handle errors in here because we want to gracefully continue
     // everything downstream should bail!() and bubble up here
-    // if anything fails, we just error print, return a unit, and keep going
+    // if anything fails, we just error print, return false, and keep going
+    let path = obj.path;
+    let is_dir = obj.is_dir();
 
     // first check if we should ignore this path
-    let ignore_paths = &ctx.ignore_paths;
-    match path.to_str() {
-        None => return, // skip non-utf8 paths
-        Some(name) => {
-            for ip in ignore_paths {
-                if name.ends_with(ip) {
-                    return;
-                }
+    if let Some(pattern) = ctx.ignore_paths.matched(path, is_dir) {
+        ctx.verbose_printer.print1(format!(
+            "{} -> Ignored by pattern '{}'",
+            escape_path(path),
+            pattern
+        ));
+        ctx.stats.add_ignored();
+        ctx.verbose_printer.event(Event {
+            reason: Some("ignored"),
+            pattern: Some(pattern),
+            ..Event::new(EventKind::Skip, path)
+        });
+        return true;
+    }
+
+    // with --one-file-system, a directory on another device is a mount point, leave it alone
+    if let Some(base_dev) = base_dev {
+        if is_dir && obj.stat.st_dev != base_dev {
+            ctx.verbose_printer.print1(format!(
+                "{} -> Skipping mount point, it is on another filesystem",
+                escape_path(path)
+            ));
+            ctx.stats.add_mount_point();
+            ctx.verbose_printer.event(Event {
+                reason: Some("mount point"),
+                ..Event::new(EventKind::Skip, path)
+            });
+            return true;
+        }
+    }
+
+    // a previous run already finished this whole subtree, or this file
+    let st = &obj.stat;
+    let link = match st.st_nlink > 1 && !is_dir {
+        true => Some((st.st_dev, st.st_ino)),
+        false => None,
+    };
+    if let Some(cp) = &ctx.checkpoint {
+        let done = match is_dir {
+            true => cp.is_completed(path),
+            false => cp.is_processed(path),
+        };
+        if done {
+            ctx.verbose_printer.print1(format!(
+                "{} -> Skipping, completed by a previous run",
+                escape_path(path)
+            ));
+            match is_dir {
+                true => ctx.stats.add_resumed_subtree(),
+                false => ctx.stats.add_resumed_entry(),
             }
+            ctx.verbose_printer.event(Event {
+                reason: Some("completed"),
+                ..Event::new(EventKind::Skip, path)
+            });
+            return true;
+        }
+    }
+
+    // an object with several hard links only needs to be processed through one of them
+    if let Some((dev, ino)) = link {
+        let done_before = match &ctx.checkpoint {
+            Some(cp) => cp.is_link_processed(dev, ino),
+            None => false,
+        };
+        if done_before || !ctx.hard_links.first_visit(dev, ino) {
+            ctx.verbose_printer.print1(format!(
+                "{} -> Skipping hard link, inode {} was already processed",
+                escape_path(path),
+                ino
+            ));
+            ctx.stats.add_hard_link();
+            ctx.verbose_printer.event(Event {
+                reason: Some("hard link"),
+                ..Event::new(EventKind::Skip, path)
+            });
+            return true;
         }
     }
 
-    // do the stuff to the provided Path with no recurse
-    files::process_path(&ctx, path);
+    // do the stuff to the provided object with no recurse,
+    // unless a previous run already did and only its subtree is left
+    let errors_before = errors::thread_error_count();
+    match &ctx.checkpoint {
+        Some(cp) if is_dir && cp.is_processed(path) => {
+            ctx.verbose_printer.print1(format!(
+                "{} -> Already processed by a previous run, continuing with its entries",
+                escape_path(path)
+            ));
+        }
+        checkpoint => {
+            let _busy = checkpoint.as_ref().map(|cp| cp.busy());
+            ctx.stats.add_scanned();
+            ctx.verbose_printer.event(Event::new(EventKind::Scan, path));
+            match &ctx.inventory {
+                Some(inventory) => inventory.record(ctx, obj),
+                None => files::process_path(ctx, obj),
+            }
+            // a mapping with swaps or chains must never be applied twice, even when resuming
+            if let Some(cp) = checkpoint {
+                if errors::thread_error_count() == errors_before {
+                    cp.processed(path, link);
+                }
+            }
+        }
+    }
 
     // We only want to recurse through non-symlink dirs
-    if path.is_symlink() || !path.is_dir() {
-        return;
+    if !is_dir {
+        return errors::thread_error_count() == errors_before;
     }
 
-    // then list all its children and do the stuff
-    let files = match files::get_children_paths(path) {
-        Ok(files) => files,
+    // then list all its children and do the stuff, relative to the open directory
+    let mut dir = match obj.open_dir() {
+        Ok(dir) => dir,
         Err(e) => {
-            eprintln!("{e}");
-            return;
+            ctx.error(path, &e);
+            return false;
         }
     };
+    let names = files::get_children_names(ctx, &mut dir, path);
+    let dirfd = dir.as_raw_fd();
+    let clean = errors::thread_error_count() == errors_before;
 
-    files.par_iter().for_each(move |f| {
-        run_recurse(&ctx, &f.as_path());
-    });
+    // every child runs, even after another one failed
+    let children_clean = names
+        .par_iter()
+        .map(|name| {
+            let name = Path::new(name);
+            let child_path = path.join(name);
+            match FsObject::stat_at(dirfd, name, &child_path) {
+                Ok(child) => run_recurse(ctx, &child, base_dev),
+                Err(e) => {
+                    ctx.error(&child_path, &e);
+                    false
+                }
+            }
+        })
+        .reduce(|| true, |a, b| a && b);
 
+    // a subtree with errors stays incomplete, so a resumed run retries what failed
+    if !clean || !children_clean {
+        return false;
+    }
+    if let Some(cp) = &ctx.checkpoint {
+        cp.complete(path, &names);
+    }
+    true
 }
 
 /// Primary entrypoint for starting the application after parsing command-line args
@@ -66,6 +191,104 @@ where
     P: AsRef<Path>,
 {
     for p in paths {
-        run_recurse(&ctx, p.as_ref());
+        let p = p.as_ref();
+        // like every other symlink, a base path that is a symlink is changed itself and
+        // not followed, so the device comes from the same unfollowed object
+        let obj = match FsObject::base(p) {
+            Ok(obj) => obj,
+            Err(e) => {
+                ctx.error(p, &e);
+                continue;
+            }
+        };
+        let base_dev = match ctx.one_file_system {
+            true => Some(obj.stat.st_dev),
+            false => None,
+        };
+        run_recurse(ctx, &obj, base_dev);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::ConflictPolicy;
+    use crate::checkpoint::Checkpoint;
+    use crate::errors::ErrorRegistry;
+    use crate::events::OutputFormat;
+    use crate::links::HardLinks;
+    use crate::patterns::IgnorePatterns;
+    use crate::stats::Stats;
+    use crate::support::AclSupport;
+    use crate::util::VerbosePrinter;
+    use nix::unistd::{chown, Uid};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+    use std::sync::Arc;
+
+    fn swap_ctx(checkpoint: Arc<Checkpoint>) -> Ctx {
+        let uidmap = HashMap::from([(1, 2), (2, 1)]);
+        Ctx {
+            noop: false,
+            skip_permissions: false,
+            skip_acls: true,
+            drop_setid: false,
+            acl_conflict: ConflictPolicy::Union,
+            recalculate_mask: false,
+            stats: Stats::new(&uidmap, &HashMap::new()),
+            uidmap,
+            gidmap: HashMap::new(),
+            one_file_system: false,
+            hard_links: HardLinks::default(),
+            acl_support: AclSupport::default(),
+            ignore_paths: IgnorePatterns::empty(),
+            journal: None,
+            checkpoint: Some(checkpoint),
+            inventory: None,
+            errors: ErrorRegistry::default(),
+            verbose_printer: VerbosePrinter::new(0, OutputFormat::Text),
+        }
+    }
+
+    #[test]
+    fn resumed_swap_changes_every_owner_once() {
+        // changing owners needs root
+        if !Uid::effective().is_root() {
+            return;
+        }
+        let root = std::env::temp_dir().join(format!("chowner-resume-{}", std::process::id()));
+        let cp_path = root.with_extension("checkpoint");
+        let _ = fs::remove_dir_all(&root);
+        let mut expected = vec![];
+        for dir in ["a", "b"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+            for (name, uid) in [("1", 1), ("2", 2)] {
+                let p = root.join(dir).join(name);
+                fs::write(&p, "").unwrap();
+                chown(&p, Some(Uid::from(uid)), None).unwrap();
+                expected.push((p, 3 - uid));
+            }
+        }
+        fs::hard_link(root.join("b/2"), root.join("link")).unwrap();
+
+        // the first run is interrupted after one file of a and all of b
+        let first = Arc::new(Checkpoint::new(&cp_path, "swap".into()));
+        let ctx = swap_ctx(first.clone());
+        for p in [root.join("a/1"), root.join("b")] {
+            assert!(run_recurse(&ctx, &FsObject::base(&p).unwrap(), None));
+        }
+        first.save().unwrap();
+
+        let resumed = Arc::new(Checkpoint::resume(&cp_path, "swap".into()).unwrap());
+        let ctx = swap_ctx(resumed.clone());
+        assert!(run_recurse(&ctx, &FsObject::base(&root).unwrap(), None));
+        assert!(resumed.is_completed(&root));
+
+        for (p, uid) in expected {
+            assert_eq!(fs::symlink_metadata(&p).unwrap().uid(), uid, "{p:?}");
+        }
+        fs::remove_dir_all(&root).unwrap();
+        fs::remove_file(&cp_path).unwrap();
     }
 }
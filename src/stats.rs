@@ -0,0 +1,309 @@
+use crate::events::OutputFormat;
+use crate::types::{AclType, PermissionType};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters for a single old:new mapping
+#[derive(Debug, Default)]
+pub struct PairStats {
+    /// The id that `old` is being changed to
+    new_id: u32,
+    /// Number of objects whose owner or group was changed
+    ownership: AtomicU64,
+    /// Number of access ACL entries rewritten
+    access_acl: AtomicU64,
+    /// Number of default ACL entries rewritten
+    default_acl: AtomicU64,
+}
+
+/// Thread-safe counters collected over the whole run, printed at the end
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// Number of filesystem objects processed
+    scanned: AtomicU64,
+    /// Number of objects whose owner or group was changed, once per object
+    changed: AtomicU64,
+    /// Number of paths skipped because they matched an ignore pattern
+    ignored: AtomicU64,
+    /// Number of subtrees skipped because a previous run completed them
+    resumed_subtrees: AtomicU64,
+    /// Number of single entries skipped because a previous run processed them
+    resumed_entries: AtomicU64,
+    /// Number of mount points skipped with `--one-file-system`
+    mount_points: AtomicU64,
+    /// Number of redundant visits to an already processed hard linked object
+    hard_links: AtomicU64,
+    /// Number of objects whose setuid/setgid bits were put back after a chown
+    setid_restored: AtomicU64,
+    /// Number of objects whose file capabilities were put back after a chown
+    capabilities_restored: AtomicU64,
+    /// Number of rewritten ACL entries whose new id already had an entry
+    acl_conflicts: AtomicU64,
+    /// Number of ACL entries whose effective permissions changed when rewritten
+    effective_changes: AtomicU64,
+    /// Counters per uid mapping, keyed by old uid
+    uid_pairs: HashMap<u32, PairStats>,
+    /// Counters per gid mapping, keyed by old gid
+    gid_pairs: HashMap<u32, PairStats>,
+}
+
+impl Stats {
+    /// Builds a `Stats` with a set of counters for every mapping pair
+    ///
+    /// # Arguments
+    ///
+    /// * `uidmap` - Map of old:new uids
+    ///
+    /// * `gidmap` - Map of old:new gids
+    ///
+    pub fn new(uidmap: &HashMap<u32, u32>, gidmap: &HashMap<u32, u32>) -> Self {
+        let pairs = |idmap: &HashMap<u32, u32>| {
+            idmap
+                .iter()
+                .map(|(old, new)| {
+                    (
+                        *old,
+                        PairStats {
+                            new_id: *new,
+                            ..Default::default()
+                        },
+                    )
+                })
+                .collect()
+        };
+        Self {
+            uid_pairs: pairs(uidmap),
+            gid_pairs: pairs(gidmap),
+            ..Default::default()
+        }
+    }
+
+    fn pair(&self, ptype: PermissionType, old_id: u32) -> Option<&PairStats> {
+        match ptype {
+            PermissionType::User => self.uid_pairs.get(&old_id),
+            PermissionType::Group => self.gid_pairs.get(&old_id),
+        }
+    }
+
+    pub fn add_scanned(&self) {
+        self.scanned.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_ignored(&self) {
+        self.ignored.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_resumed_subtree(&self) {
+        self.resumed_subtrees.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_resumed_entry(&self) {
+        self.resumed_entries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_mount_point(&self) {
+        self.mount_points.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_hard_link(&self) {
+        self.hard_links.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_setid_restored(&self) {
+        self.setid_restored.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_capability_restored(&self) {
+        self.capabilities_restored.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_acl_conflict(&self) {
+        self.acl_conflicts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_effective_change(&self) {
+        self.effective_changes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of filesystem objects processed so far
+    pub fn scanned(&self) -> u64 {
+        self.scanned.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of objects whose owner or group was changed so far
+    pub fn changed(&self) -> u64 {
+        self.changed.load(Ordering::Relaxed)
+    }
+
+    /// Returns the rewritten ACL entries so far, over all mappings
+    pub fn acl_entries(&self) -> u64 {
+        let mut acl_entries = 0;
+        for p in self.uid_pairs.values().chain(self.gid_pairs.values()) {
+            acl_entries += p.access_acl.load(Ordering::Relaxed);
+            acl_entries += p.default_acl.load(Ordering::Relaxed);
+        }
+        acl_entries
+    }
+
+    /// Counts an object whose owner or group was changed, its ids are counted with `add_ownership`
+    pub fn add_changed(&self) {
+        self.changed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts an ownership change from `old_id`
+    pub fn add_ownership(&self, ptype: PermissionType, old_id: u32) {
+        if let Some(p) = self.pair(ptype, old_id) {
+            p.ownership.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts a rewritten ACL entry for `old_id`
+    pub fn add_acl_entry(&self, ptype: PermissionType, old_id: u32, atype: AclType) {
+        if let Some(p) = self.pair(ptype, old_id) {
+            match atype {
+                AclType::Access => p.access_acl.fetch_add(1, Ordering::Relaxed),
+                AclType::Default => p.default_acl.fetch_add(1, Ordering::Relaxed),
+            };
+        }
+    }
+
+    /// Prints the end of run summary table to stdout
+    ///
+    /// # Arguments
+    ///
+    /// * `noop` - Whether this was a dry run, so nothing was actually changed
+    ///
+    /// * `output` - Output format, JSON Lines prints the summary as a single object
+    ///
+    pub fn print_summary(&self, noop: bool, output: OutputFormat) {
+        let load = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        let mut ownership = 0;
+        let mut access_acl = 0;
+        let mut default_acl = 0;
+        let mut rows: Vec<(&str, u32, &PairStats)> = vec![];
+        for (kind, pairs) in [("uid", &self.uid_pairs), ("gid", &self.gid_pairs)] {
+            let mut sorted: Vec<(&u32, &PairStats)> = pairs.iter().collect();
+            sorted.sort_by_key(|(old, _)| **old);
+            for (old, p) in sorted {
+                ownership += load(&p.ownership);
+                access_acl += load(&p.access_acl);
+                default_acl += load(&p.default_acl);
+                rows.push((kind, *old, p));
+            }
+        }
+
+        if output == OutputFormat::Jsonl {
+            let pairs: Vec<serde_json::Value> = rows
+                .iter()
+                .map(|(kind, old, p)| {
+                    json!({
+                        "type": kind,
+                        "old_id": old,
+                        "new_id": p.new_id,
+                        "ownership": load(&p.ownership),
+                        "access_acl": load(&p.access_acl),
+                        "default_acl": load(&p.default_acl),
+                    })
+                })
+                .collect();
+            let summary = json!({
+                "kind": "summary",
+                "noop": noop,
+                "scanned": load(&self.scanned),
+                "changed": load(&self.changed),
+                "ignored": load(&self.ignored),
+                "mount_points": load(&self.mount_points),
+                "resumed_subtrees": load(&self.resumed_subtrees),
+                "resumed_entries": load(&self.resumed_entries),
+                "hard_links": load(&self.hard_links),
+                "setid_restored": load(&self.setid_restored),
+                "capabilities_restored": load(&self.capabilities_restored),
+                "acl_conflicts": load(&self.acl_conflicts),
+                "effective_changes": load(&self.effective_changes),
+                "ownership": ownership,
+                "access_acl": access_acl,
+                "default_acl": default_acl,
+                "pairs": pairs,
+            });
+            println!("{summary}");
+            return;
+        }
+
+        println!();
+        match noop {
+            true => println!("Summary (NOOP: counts are changes that would have been made)"),
+            false => println!("Summary"),
+        }
+        println!("  {:<22}{:>12}", "entries scanned", load(&self.scanned));
+        println!("  {:<22}{:>12}", "entries ignored", load(&self.ignored));
+        if load(&self.resumed_subtrees) > 0 {
+            println!(
+                "  {:<22}{:>12}",
+                "subtrees resumed",
+                load(&self.resumed_subtrees)
+            );
+        }
+        if load(&self.resumed_entries) > 0 {
+            println!(
+                "  {:<22}{:>12}",
+                "entries resumed",
+                load(&self.resumed_entries)
+            );
+        }
+        if load(&self.setid_restored) > 0 {
+            println!(
+                "  {:<22}{:>12}",
+                "setid bits restored",
+                load(&self.setid_restored)
+            );
+        }
+        if load(&self.capabilities_restored) > 0 {
+            println!(
+                "  {:<22}{:>12}",
+                "capabilities restored",
+                load(&self.capabilities_restored)
+            );
+        }
+        if load(&self.hard_links) > 0 {
+            println!(
+                "  {:<22}{:>12}",
+                "hard links skipped",
+                load(&self.hard_links)
+            );
+        }
+        if load(&self.mount_points) > 0 {
+            println!(
+                "  {:<22}{:>12}",
+                "mount points skipped",
+                load(&self.mount_points)
+            );
+        }
+        println!("  {:<22}{:>12}", "entries changed", load(&self.changed));
+        println!("  {:<22}{:>12}", "id changes", ownership);
+        println!("  {:<22}{:>12}", "access ACL entries", access_acl);
+        println!("  {:<22}{:>12}", "default ACL entries", default_acl);
+        if load(&self.acl_conflicts) > 0 {
+            println!("  {:<22}{:>12}", "ACL conflicts", load(&self.acl_conflicts));
+        }
+        if rows.is_empty() {
+            return;
+        }
+        println!();
+        println!(
+            "  {:<5}{:>12}{:>12}{:>12}{:>12}{:>12}",
+            "type", "old", "new", "ownership", "access", "default"
+        );
+        for (kind, old, p) in rows {
+            println!(
+                "  {:<5}{:>12}{:>12}{:>12}{:>12}{:>12}",
+                kind,
+                old,
+                p.new_id,
+                load(&p.ownership),
+                load(&p.access_acl),
+                load(&p.default_acl),
+            );
+        }
+    }
+}
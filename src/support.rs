@@ -0,0 +1,187 @@
+use crate::events::OutputFormat;
+use crate::util::{escape_path, insert_path, read, write};
+use nix::sys::stat::{major, minor};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsString;
+use std::fs;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+/// Shared set of devices whose filesystem doesn't support POSIX ACLs, so ACL
+/// work is skipped for the rest of the filesystem after the first failure
+#[derive(Debug, Default)]
+pub struct AclSupport {
+    /// First path that failed on each device, as an example.
+    /// Read for every object but written at most once per device, hence the `RwLock`
+    unsupported: RwLock<BTreeMap<u64, PathBuf>>,
+    /// Devices an object was already probed on, written once per device like `unsupported`
+    probed: RwLock<HashSet<u64>>,
+}
+
+/// Reverses the octal escapes of `/proc/self/mountinfo` fields, such as `\040` for a space
+fn unescape_mountinfo(field: &str) -> Vec<u8> {
+    let bytes = field.as_bytes();
+    let mut out = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4).and_then(|o| {
+            let o = std::str::from_utf8(o).ok()?;
+            u8::from_str_radix(o, 8).ok()
+        });
+        match (bytes[i], octal) {
+            (b'\\', Some(b)) => {
+                out.push(b);
+                i += 4;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+/// Returns the mount point and filesystem type of a device from `/proc/self/mountinfo`
+fn mount_of(dev: u64) -> Option<(PathBuf, String)> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").ok()?;
+    let id = format!("{}:{}", major(dev), minor(dev));
+    for line in mountinfo.lines() {
+        // 36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue
+        let fields: Vec<&str> = line.split(' ').collect();
+        if fields.get(2) != Some(&id.as_str()) {
+            continue;
+        }
+        let sep = fields.iter().position(|f| *f == "-")?;
+        let mount_point = PathBuf::from(OsString::from_vec(unescape_mountinfo(fields.get(4)?)));
+        let fstype =
+            String::from_utf8_lossy(&unescape_mountinfo(fields.get(sep + 1)?)).into_owned();
+        return Some((mount_point, fstype));
+    }
+    None
+}
+
+impl AclSupport {
+    /// Returns true if ACLs already failed as unsupported on the device
+    pub fn is_unsupported(&self, dev: u64) -> bool {
+        read(&self.unsupported).contains_key(&dev)
+    }
+
+    /// Returns true for the first object on a device, whose ACLs should be read
+    /// even without ACL xattrs, since a filesystem without ACL support lists none
+    pub fn first_on_device(&self, dev: u64) -> bool {
+        let probed = read(&self.probed);
+        if probed.contains(&dev) {
+            return false;
+        }
+        drop(probed);
+        let mut probed = write(&self.probed);
+        probed.insert(dev)
+    }
+
+    /// Marks the device as not supporting ACLs, returns true the first time
+    ///
+    /// # Arguments
+    ///
+    /// * `dev` - Device the object lives on
+    ///
+    /// * `path` - Path that failed, kept as an example
+    ///
+    pub fn mark_unsupported(&self, dev: u64, path: &Path) -> bool {
+        if self.is_unsupported(dev) {
+            return false;
+        }
+        // another thread may have marked it in between, only the first insert counts
+        let mut unsupported = write(&self.unsupported);
+        if unsupported.contains_key(&dev) {
+            return false;
+        }
+        unsupported.insert(dev, path.to_path_buf());
+        true
+    }
+
+    /// Prints the filesystems ACLs were skipped on, nothing if there are none
+    ///
+    /// # Arguments
+    ///
+    /// * `output` - Output format, JSON Lines prints one object per filesystem
+    ///
+    pub fn print_summary(&self, output: OutputFormat) {
+        let unsupported = read(&self.unsupported);
+        if unsupported.is_empty() {
+            return;
+        }
+        if output == OutputFormat::Text {
+            println!();
+            println!("ACLs not supported, skipped on ({})", unsupported.len());
+        }
+        for (dev, example) in unsupported.iter() {
+            let device = format!("{}:{}", major(*dev), minor(*dev));
+            let (mount_point, fstype) = match mount_of(*dev) {
+                Some((m, t)) => (Some(m), Some(t)),
+                None => (None, None),
+            };
+            match output {
+                OutputFormat::Jsonl => {
+                    let mut obj = Map::new();
+                    obj.insert("kind".into(), json!("acl_unsupported"));
+                    obj.insert("device".into(), json!(device));
+                    match &mount_point {
+                        Some(m) => insert_path(&mut obj, "mount_point", m),
+                        None => {
+                            obj.insert("mount_point".into(), Value::Null);
+                        }
+                    }
+                    obj.insert("fstype".into(), json!(fstype));
+                    insert_path(&mut obj, "example", example);
+                    println!("{}", Value::Object(obj));
+                }
+                OutputFormat::Text => println!(
+                    "  {:<10}{:<10}{:<30}  e.g. {}",
+                    device,
+                    fstype.as_deref().unwrap_or("?"),
+                    mount_point.as_deref().map_or("?".into(), escape_path),
+                    escape_path(example)
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_is_marked_once() {
+        let support = AclSupport::default();
+        assert!(!support.is_unsupported(39));
+        assert!(support.mark_unsupported(39, Path::new("/mnt/a")));
+        assert!(!support.mark_unsupported(39, Path::new("/mnt/b")));
+        assert!(support.is_unsupported(39));
+        assert_eq!(read(&support.unsupported)[&39], PathBuf::from("/mnt/a"));
+    }
+
+    #[test]
+    fn only_first_object_on_a_device_is_probed() {
+        let support = AclSupport::default();
+        assert!(support.first_on_device(39));
+        assert!(!support.first_on_device(39));
+        assert!(support.first_on_device(40));
+    }
+
+    #[test]
+    fn mountinfo_fields_are_unescaped() {
+        assert_eq!(
+            unescape_mountinfo("/mnt/with\\040space"),
+            b"/mnt/with space"
+        );
+        assert_eq!(
+            unescape_mountinfo("/mnt/back\\134slash"),
+            b"/mnt/back\\slash"
+        );
+        assert_eq!(unescape_mountinfo("/mnt/odd\\9"), b"/mnt/odd\\9");
+    }
+}
@@ -1,6 +1,7 @@
 use core::fmt;
 
 /// What type of permission we're expecting
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PermissionType {
     User,
     Group,
@@ -15,10 +16,39 @@ impl fmt::Display for PermissionType {
     }
 }
 
+impl PermissionType {
+    /// Short lowercase name, used in the journal
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PermissionType::User => "user",
+            PermissionType::Group => "group",
+        }
+    }
+}
+
 /// Two types of Posix ACLs
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum AclType {
     /// Access ACL is the normal acl type on files and directories
     Access,
     /// Default ACLs are only present on directories, and govern ACL inheritance
     Default,
 }
+
+impl AclType {
+    /// Short lowercase name, used in messages and the journal
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AclType::Access => "access",
+            AclType::Default => "default",
+        }
+    }
+
+    /// Extended attribute the ACL is stored in
+    pub fn xattr_name(&self) -> &'static str {
+        match self {
+            AclType::Access => "system.posix_acl_access",
+            AclType::Default => "system.posix_acl_default",
+        }
+    }
+}
@@ -1,21 +1,41 @@
-/// Use `print1`, `print2`, and `print3` for different levels of verbosity
+use crate::events::{Event, OutputFormat};
+use anyhow::bail;
+use serde_json::{json, Map, Value};
+use std::ffi::OsString;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Use `print1`, `print2`, and `print3` for different levels of verbosity.
+/// Use `event` for structured output, which replaces the messages when
+/// the output format is JSON Lines.
 ///
 #[derive(Debug)]
 pub struct VerbosePrinter {
     verbosity_level: u8,
+    output: OutputFormat,
 }
 
 impl VerbosePrinter {
-    pub fn new(verbosity_level: u8) -> Self {
-        return Self { verbosity_level }
+    pub fn new(verbosity_level: u8, output: OutputFormat) -> Self {
+        return Self {
+            verbosity_level,
+            output,
+        };
     }
 
     pub fn print1(&self, message: String) {
-        if self.verbosity_level >= 1 {
+        if self.output == OutputFormat::Text && self.verbosity_level >= 1 {
             println!("{}", message);
         }
     }
 
+    pub fn event(&self, event: Event) {
+        if self.output == OutputFormat::Jsonl {
+            println!("{}", event.to_json());
+        }
+    }
+
     // pub fn print2(&self, message: String) {
     //     if self.verbosity_level >= 2 {
     //         println!("{}", message);
@@ -28,3 +48,161 @@ impl VerbosePrinter {
     //     }
     // }
 }
+
+/// Locks a mutex shared between threads, ignoring poisoning. That only means another
+/// thread panicked while holding the lock, and the sets, maps and counters locked this
+/// way stay consistent between single updates, so the run can go on with them.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Locks an `RwLock` for reading, ignoring poisoning like `lock`
+pub fn read<T>(rwlock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    rwlock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Locks an `RwLock` for writing, ignoring poisoning like `lock`
+pub fn write<T>(rwlock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    rwlock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Returns `path` as an absolute path, resolved against the current directory.
+/// Falls back to `path` itself if the current directory can't be read.
+pub fn absolute_path(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Returns a printable version of a path, where tabs, newlines, backslashes
+/// and any bytes that are not valid UTF-8 are escaped.
+/// The result can be turned back into the original path with `unescape_path`.
+///
+/// # Arguments
+///
+/// * `path` - Path to escape
+///
+pub fn escape_path(path: &Path) -> String {
+    let mut out = String::new();
+    let mut bytes = path.as_os_str().as_bytes();
+    while !bytes.is_empty() {
+        let (valid, rest) = match std::str::from_utf8(bytes) {
+            Ok(s) => (s, &bytes[bytes.len()..]),
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                // valid_up_to guarantees this slice is valid UTF-8
+                (std::str::from_utf8(valid).unwrap_or_default(), rest)
+            }
+        };
+        for c in valid.chars() {
+            match c {
+                '\\' => out.push_str("\\\\"),
+                '\t' => out.push_str("\\t"),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                _ => out.push(c),
+            }
+        }
+        // escape a single invalid byte and continue with whatever is left
+        bytes = match rest.split_first() {
+            Some((b, rest)) => {
+                out.push_str(&format!("\\x{:02x}", b));
+                rest
+            }
+            None => rest,
+        };
+    }
+    out
+}
+
+/// Inserts a path into a JSON object. The `key` field holds the path as text,
+/// with invalid UTF-8 replaced, and `<key>_bytes` holds the raw bytes of names
+/// that are not valid UTF-8, so JSON consumers always get the real path.
+///
+/// # Arguments
+///
+/// * `obj` - JSON object to insert into
+///
+/// * `key` - Name of the field
+///
+/// * `path` - Path to insert
+///
+pub fn insert_path(obj: &mut Map<String, Value>, key: &str, path: &Path) {
+    obj.insert(key.into(), json!(path.to_string_lossy()));
+    if path.to_str().is_none() {
+        obj.insert(format!("{key}_bytes"), json!(path.as_os_str().as_bytes()));
+    }
+}
+
+/// Reverses `escape_path`, returning the original path
+///
+/// # Arguments
+///
+/// * `escaped` - String previously returned from `escape_path`
+///
+pub fn unescape_path(escaped: &str) -> Result<PathBuf, anyhow::Error> {
+    let mut out: Vec<u8> = vec![];
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => out.push(b'\\'),
+            Some('t') => out.push(b'\t'),
+            Some('n') => out.push(b'\n'),
+            Some('r') => out.push(b'\r'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(b) => out.push(b),
+                    Err(_) => bail!("Invalid escape sequence '\\x{hex}' in '{escaped}'"),
+                }
+            }
+            Some(o) => bail!("Invalid escape sequence '\\{o}' in '{escaped}'"),
+            None => bail!("Trailing backslash in '{escaped}'"),
+        }
+    }
+    Ok(PathBuf::from(OsString::from_vec(out)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_path_roundtrips_special_characters() {
+        let path = PathBuf::from(OsString::from_vec(
+            b"/data/tab\there/new\nline/back\\slash/latin\xe9/\xe2\x9c\x93".to_vec(),
+        ));
+        let escaped = escape_path(&path);
+        assert_eq!(
+            escaped,
+            "/data/tab\\there/new\\nline/back\\\\slash/latin\\xe9/\u{2713}"
+        );
+        assert_eq!(unescape_path(&escaped).unwrap(), path);
+    }
+
+    #[test]
+    fn insert_path_keeps_raw_bytes_of_non_utf8_names() {
+        let mut obj = Map::new();
+        insert_path(&mut obj, "path", Path::new("/data/tab\there"));
+        assert_eq!(
+            Value::Object(obj).to_string(),
+            r#"{"path":"/data/tab\there"}"#
+        );
+
+        let mut obj = Map::new();
+        let path = PathBuf::from(OsString::from_vec(b"/a\xe9".to_vec()));
+        insert_path(&mut obj, "path", &path);
+        assert_eq!(obj["path"], json!("/a\u{fffd}"));
+        assert_eq!(obj["path_bytes"], json!([47, 97, 233]));
+    }
+
+    #[test]
+    fn unescape_path_rejects_bad_escapes() {
+        assert!(unescape_path("/data/\\q").is_err());
+        assert!(unescape_path("/data/\\xzz").is_err());
+        assert!(unescape_path("/data/\\").is_err());
+    }
+}